@@ -231,7 +231,10 @@ function New-DesktopShortcut {
     }
 }
 
-# Function to manage the Scheduled Task for autostart
+# Function to manage the Scheduled Task for autostart.
+# The app also has a Windows service (`service install`), which is not used here: it runs as
+# LocalSystem, so it would execute the binary from the user-writable $InstallDir with full
+# rights, could not read the user's keyring and does not support --only-while-playing.
 function Set-StartupTask {
     param(
         [Parameter(Mandatory = $true)]
@@ -42,6 +42,22 @@ in {
       description = "Whether to enable Statlocker integration (sends match IDs to statlocker.gg after ingestion)";
     };
 
+    onlyWhilePlaying = mkOption {
+      type = types.bool;
+      default = false;
+      description = "Only watch the Steam cache while Deadlock is running (and shortly after), to minimise background resource use";
+    };
+
+    apiKeyFile = mkOption {
+      type = types.nullOr types.path;
+      default = null;
+      example = "/run/secrets/deadlock-api-key";
+      description = ''
+        File holding an API key for trusted ingesters, sent with uploads.
+        Use a path outside the Nix store, as the store is world-readable.
+      '';
+    };
+
     steamUser = mkOption {
       type = types.nullOr types.str;
       default = cfg.user;
@@ -81,10 +97,18 @@ in {
       wants = [ "network-online.target" ];
 
       serviceConfig = {
-        Type = "simple";
+        # Reports readiness after the initial scan and pings the watchdog from the watcher loop,
+        # between upload retries and while walking the cache. A single API call is bounded by the
+        # connect timeout plus twice the read timeout (70s by default), so leave room for one.
+        Type = "notify";
+        NotifyAccess = "main";
+        WatchdogSec = "3min";
+        # The initial scan uploads everything found in the cache, which can take a while. Progress
+        # during the scan extends this timeout, so it only limits a startup that is stuck.
+        TimeoutStartSec = "5min";
         User = cfg.user;
         Group = cfg.group;
-        ExecStart = "${cfg.package}/bin/deadlock-api-ingest${lib.optionalString (!cfg.statlocker.enable) " --no-statlocker"}";
+        ExecStart = "${cfg.package}/bin/deadlock-api-ingest${lib.optionalString (!cfg.statlocker.enable) " --no-statlocker"}${lib.optionalString cfg.onlyWhilePlaying " --only-while-playing"}${lib.optionalString (cfg.apiKeyFile != null) " --api-key-file ${toString cfg.apiKeyFile}"}";
         Restart = "on-failure";
         RestartSec = "10s";
 
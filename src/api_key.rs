@@ -0,0 +1,129 @@
+//! Optional API key for trusted ingesters, sent as a bearer token with salt uploads so the
+//! Deadlock API can grant them higher rate limits and trust levels.
+//!
+//! The key is taken from the `DEADLOCK_API_KEY` environment variable, the file given with
+//! `--api-key-file`, or the OS keyring (see `api-key set`), in that order. It is never logged and
+//! never stored in the local history.
+
+use clap::Subcommand;
+use core::fmt::{Debug, Formatter};
+use std::path::Path;
+use std::sync::OnceLock;
+use tracing::{debug, info, warn};
+
+const ENV_VAR: &str = "DEADLOCK_API_KEY";
+
+/// Service and user name of the keyring entry
+const KEYRING_SERVICE: &str = "deadlock-api-ingest";
+const KEYRING_USER: &str = "api-key";
+
+static API_KEY: OnceLock<Option<ApiKey>> = OnceLock::new();
+
+#[derive(Subcommand, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ApiKeyCommand {
+    /// Store the API key read from stdin in the OS keyring
+    Set,
+    /// Remove the API key from the OS keyring
+    Remove,
+}
+
+/// An API key, redacted when formatted so it cannot end up in the logs by accident.
+#[derive(Clone, PartialEq, Eq)]
+pub(crate) struct ApiKey(String);
+
+impl ApiKey {
+    pub(crate) fn parse(key: &str) -> Option<Self> {
+        let key = key.trim();
+        (!key.is_empty()).then(|| Self(key.to_string()))
+    }
+
+    /// Value of the `Authorization` header.
+    pub(crate) fn bearer(&self) -> String {
+        format!("Bearer {}", self.0)
+    }
+}
+
+impl Debug for ApiKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.write_str("ApiKey(<redacted>)")
+    }
+}
+
+/// Loads the API key from the first source that has one. Only fails if `file` cannot be read.
+pub(crate) fn init(file: Option<&Path>) -> Result<(), String> {
+    let (key, source) = if let Some(key) = std::env::var(ENV_VAR)
+        .ok()
+        .and_then(|key| ApiKey::parse(&key))
+    {
+        (Some(key), format!("the {ENV_VAR} environment variable"))
+    } else if let Some(file) = file {
+        let content = std::fs::read_to_string(file)
+            .map_err(|e| format!("Failed to read API key file {}: {e}", file.display()))?;
+        let key = ApiKey::parse(&content)
+            .ok_or_else(|| format!("API key file {} is empty", file.display()))?;
+        (Some(key), file.display().to_string())
+    } else {
+        (from_keyring(), "the OS keyring".to_string())
+    };
+    if key.is_some() {
+        info!("Authenticating uploads with the API key from {source}");
+    }
+    if API_KEY.set(key).is_err() {
+        warn!("API key was already configured");
+    }
+    Ok(())
+}
+
+/// Returns the API key to send with uploads, if one is configured.
+pub(crate) fn get() -> Option<&'static ApiKey> {
+    API_KEY.get()?.as_ref()
+}
+
+fn from_keyring() -> Option<ApiKey> {
+    let password = keyring::Entry::new(KEYRING_SERVICE, KEYRING_USER)
+        .and_then(|entry| entry.get_password())
+        .inspect_err(|e| debug!("No API key in the OS keyring: {e}"))
+        .ok()?;
+    ApiKey::parse(&password)
+}
+
+/// Runs an `api-key` subcommand.
+pub(crate) fn manage(command: ApiKeyCommand) -> Result<(), String> {
+    let entry = keyring::Entry::new(KEYRING_SERVICE, KEYRING_USER).map_err(|e| {
+        format!("Failed to open the OS keyring ({e}), use {ENV_VAR} or --api-key-file instead")
+    })?;
+    match command {
+        ApiKeyCommand::Set => {
+            let mut line = String::new();
+            std::io::stdin()
+                .read_line(&mut line)
+                .map_err(|e| format!("Failed to read the API key from stdin: {e}"))?;
+            let key = ApiKey::parse(&line).ok_or("No API key given on stdin")?;
+            entry
+                .set_password(&key.0)
+                .map_err(|e| format!("Failed to store the API key in the OS keyring: {e}"))?;
+            info!("Stored the API key in the OS keyring");
+        }
+        ApiKeyCommand::Remove => {
+            entry
+                .delete_credential()
+                .map_err(|e| format!("Failed to remove the API key from the OS keyring: {e}"))?;
+            info!("Removed the API key from the OS keyring");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_api_key_is_redacted() {
+        let key = ApiKey::parse("  secret-key\n").unwrap();
+        assert_eq!(key.bearer(), "Bearer secret-key");
+        assert_eq!(format!("{key:?}"), "ApiKey(<redacted>)");
+        assert!(!format!("{:?}", Some(&key)).contains("secret"));
+        assert_eq!(ApiKey::parse(" \n"), None);
+    }
+}
@@ -2,11 +2,24 @@ use core::fmt::Debug;
 use core::fmt::Display;
 
 pub(crate) enum Error {
-    MatchIdTooLarge,
+    InvalidSalts(String),
     FailedToIngest(String),
+    Interrupted,
+    /// The API refuses uploads from this version, see [`crate::kill_switch`]
+    Paused(String),
     Ureq(ureq::Error),
 }
 
+impl Error {
+    /// Returns true if retrying the same request can never succeed.
+    pub(crate) fn is_permanent(&self) -> bool {
+        matches!(
+            self,
+            Error::InvalidSalts(_) | Error::Ureq(ureq::Error::StatusCode(400))
+        )
+    }
+}
+
 impl core::error::Error for Error {}
 
 impl Display for Error {
@@ -18,8 +31,10 @@ impl Display for Error {
 impl Debug for Error {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         match self {
-            Error::MatchIdTooLarge => write!(f, "Match ID too large"),
+            Error::InvalidSalts(s) => write!(f, "Invalid salts: {s}"),
             Error::FailedToIngest(s) => write!(f, "Failed to ingest: {s}"),
+            Error::Interrupted => write!(f, "Interrupted by shutdown"),
+            Error::Paused(reason) => write!(f, "Uploads are paused because {reason}"),
             Error::Ureq(e) => write!(f, "Ureq error: {e:?}"),
         }
     }
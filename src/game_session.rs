@@ -0,0 +1,153 @@
+use crate::scan_cache::DEADLOCK_APP_ID;
+use core::time::Duration;
+use std::time::Instant;
+use tracing::info;
+
+/// How long the watcher stays active after the game has exited, to catch salts written late
+const GRACE_PERIOD: Duration = Duration::from_mins(5);
+
+/// How often the running state is checked while the watcher is active
+const CHECK_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Tracks whether a play session is in progress, for `--only-while-playing`.
+pub(crate) struct SessionMonitor {
+    last_check: Option<Instant>,
+    last_seen_running: Option<Instant>,
+}
+
+impl SessionMonitor {
+    pub(crate) fn new() -> Self {
+        Self {
+            last_check: None,
+            last_seen_running: None,
+        }
+    }
+
+    /// Returns true while Deadlock is running and for a grace period after it exits.
+    /// The running state is only checked every [`CHECK_INTERVAL`], so this is cheap to call often.
+    pub(crate) fn is_active(&mut self) -> bool {
+        let now = Instant::now();
+        if self
+            .last_check
+            .is_none_or(|last_check| now.duration_since(last_check) >= CHECK_INTERVAL)
+        {
+            self.last_check = Some(now);
+            if is_game_running() {
+                if self.last_seen_running.is_none() {
+                    info!("Deadlock is running");
+                }
+                self.last_seen_running = Some(now);
+            } else if self
+                .last_seen_running
+                .take_if(|seen| now.duration_since(*seen) >= GRACE_PERIOD)
+                .is_some()
+            {
+                info!("Deadlock is no longer running");
+            }
+        }
+        self.last_seen_running.is_some()
+    }
+}
+
+/// Returns true if Steam reports Deadlock as running, or (on Linux) a Deadlock process exists.
+pub(crate) fn is_game_running() -> bool {
+    steam_reports_running() || process_running()
+}
+
+/// Reads `HKCU\Software\Valve\Steam\Apps\1422450\Running`, which Steam keeps up to date.
+#[cfg(target_os = "windows")]
+fn steam_reports_running() -> bool {
+    use winreg::RegKey;
+    use winreg::enums::HKEY_CURRENT_USER;
+
+    RegKey::predef(HKEY_CURRENT_USER)
+        .open_subkey(format!("Software\\Valve\\Steam\\Apps\\{DEADLOCK_APP_ID}"))
+        .and_then(|key| key.get_value::<u32, _>("Running"))
+        .is_ok_and(|running| running != 0)
+}
+
+/// Reads the running state from `~/.steam/registry.vdf`, Steam's registry emulation on Linux.
+#[cfg(not(target_os = "windows"))]
+fn steam_reports_running() -> bool {
+    dirs::home_dir()
+        .and_then(|home| std::fs::read_to_string(home.join(".steam").join("registry.vdf")).ok())
+        .is_some_and(|content| parse_registry_running(&content))
+}
+
+#[cfg(not(target_os = "windows"))]
+fn parse_registry_running(content: &str) -> bool {
+    let Ok(root) = crate::vdf::parse(content) else {
+        return false;
+    };
+    let Some(steam) = root.get_path(&["Registry", "HKCU", "Software", "Valve", "Steam"]) else {
+        return false;
+    };
+    steam.get_str("RunningAppID") == Some(DEADLOCK_APP_ID)
+        || steam
+            .get_path(&["Apps", DEADLOCK_APP_ID])
+            .and_then(|app| app.get_str("Running"))
+            .is_some_and(|running| running != "0")
+}
+
+/// Looks for the game process (`deadlock.exe`, running under Proton) in `/proc`,
+/// since `registry.vdf` is only written out by Steam from time to time.
+#[cfg(target_os = "linux")]
+fn process_running() -> bool {
+    let Ok(entries) = std::fs::read_dir("/proc") else {
+        return false;
+    };
+    entries.flatten().any(|entry| {
+        std::fs::read_to_string(entry.path().join("comm"))
+            .is_ok_and(|comm| comm.trim_end() == "deadlock.exe")
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn process_running() -> bool {
+    false
+}
+
+#[cfg(all(test, not(target_os = "windows")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_registry_running() {
+        let content = r#""Registry"
+{
+	"HKCU"
+	{
+		"Software"
+		{
+			"Valve"
+			{
+				"Steam"
+				{
+					"RunningAppID"		"0"
+					"Apps"
+					{
+						"1422450"
+						{
+							"Running"		"1"
+						}
+					}
+				}
+			}
+		}
+	}
+}
+"#;
+        assert!(parse_registry_running(content));
+        assert!(!parse_registry_running(
+            &content.replace("\"Running\"\t\t\"1\"", "\"Running\"\t\t\"0\"")
+        ));
+        assert!(parse_registry_running(
+            &content
+                .replace("\"Running\"\t\t\"1\"", "\"Running\"\t\t\"0\"")
+                .replace(
+                    "\"RunningAppID\"\t\t\"0\"",
+                    "\"RunningAppID\"\t\t\"1422450\""
+                )
+        ));
+    }
+}
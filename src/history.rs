@@ -0,0 +1,714 @@
+use crate::utils::Salts;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use redb::{AccessGuard, Database, ReadableDatabase, ReadableTable, StorageError, TableDefinition};
+use serde::{Deserialize, Serialize};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{OnceLock, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{debug, info, warn};
+
+/// Name of the history database file
+const HISTORY_FILE_NAME: &str = "history.redb";
+
+/// Name of the file holding the key used to encrypt uploader identities
+const KEY_FILE_NAME: &str = "history.key";
+
+/// Name of the legacy append-only log that is imported into the history on first start
+const LEGACY_LOG_FILE_NAME: &str = "fetched-salts.jsonl";
+
+/// Maximum size of the active history file before it is rotated (256MB)
+const MAX_HISTORY_SIZE: u64 = 268_435_456;
+
+/// Number of rotated history files to keep around
+const MAX_ROTATED_FILES: usize = 4;
+
+/// Prefix marking an encrypted uploader identity
+const ENCRYPTED_PREFIX: &str = "enc:";
+
+/// Salts keyed by `(match_id, kind)`, where kind is 0 for metadata and 1 for replay salts.
+const SALTS_TABLE: TableDefinition<(u64, u8), &[u8]> = TableDefinition::new("salts");
+
+static HISTORY: OnceLock<RwLock<Option<History>>> = OnceLock::new();
+
+type Entry<'a> = (AccessGuard<'a, (u64, u8)>, AccessGuard<'a, &'static [u8]>);
+
+/// What the `history` and `export` commands ask for. Answered from the local history, or by the
+/// running instance over IPC when it has the history open.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(tag = "query", rename_all = "snake_case")]
+pub(crate) enum Query {
+    /// Every record for one match
+    Match { match_id: u64 },
+    /// Up to `limit` records with the highest match ids, i.e. of the most recently played matches
+    Recent { limit: usize },
+    /// Every record
+    All,
+}
+
+/// A single ingested salt as stored in the history.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct HistoryRecord {
+    pub(crate) match_id: u64,
+    pub(crate) cluster_id: u32,
+    pub(crate) metadata_salt: Option<u32>,
+    pub(crate) replay_salt: Option<u32>,
+    /// Identity of the Steam account the salt was attributed to when it was found.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) uploader: Option<String>,
+    pub(crate) ingested_at: u64,
+}
+
+impl HistoryRecord {
+    fn from_salts(salt: &Salts) -> Self {
+        Self {
+            match_id: salt.match_id,
+            cluster_id: salt.cluster_id,
+            metadata_salt: salt.metadata_salt,
+            replay_salt: salt.replay_salt,
+            uploader: salt.username.map(|u| u.to_string()),
+            ingested_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+        }
+    }
+
+    fn keys(&self) -> impl Iterator<Item = (u64, u8)> {
+        let metadata = self.metadata_salt.map(|_| (self.match_id, 0));
+        let replay = self.replay_salt.map(|_| (self.match_id, 1));
+        metadata.into_iter().chain(replay)
+    }
+}
+
+/// Local, crash-safe store of every salt that was successfully ingested.
+pub(crate) struct History {
+    path: PathBuf,
+    db: Database,
+    rotated: Vec<Database>,
+    key: Option<[u8; 32]>,
+    encrypt: bool,
+}
+
+impl History {
+    /// Opens (or creates) the history at `path`. Uploader identities of new records are encrypted
+    /// when `encrypt` is set; existing encrypted records can be read whenever `key` is given.
+    pub(crate) fn open(
+        path: &Path,
+        key: Option<[u8; 32]>,
+        encrypt: bool,
+    ) -> Result<Self, redb::Error> {
+        let db = Database::create(path)?;
+        let txn = db.begin_write()?;
+        txn.open_table(SALTS_TABLE)?;
+        txn.commit()?;
+
+        let rotated = (1..=MAX_ROTATED_FILES)
+            .map(|i| rotated_path(path, i))
+            .filter(|p| p.exists())
+            .filter_map(|p| match Database::open(&p) {
+                Ok(db) => Some(db),
+                Err(e) => {
+                    warn!("Failed to open rotated history {}: {e}", p.display());
+                    None
+                }
+            })
+            .collect();
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            db,
+            rotated,
+            key,
+            encrypt: encrypt && key.is_some(),
+        })
+    }
+
+    fn cipher(&self) -> Option<ChaCha20Poly1305> {
+        self.key.map(|k| ChaCha20Poly1305::new(&k.into()))
+    }
+
+    pub(crate) fn insert(&self, record: &HistoryRecord) -> Result<(), redb::Error> {
+        let mut record = record.clone();
+        if self.encrypt
+            && let (Some(cipher), Some(uploader)) = (self.cipher(), &record.uploader)
+        {
+            record.uploader = encrypt(&cipher, uploader);
+        }
+        let value =
+            serde_json::to_vec(&record).map_err(|e| redb::Error::Corrupted(e.to_string()))?;
+
+        let txn = self.db.begin_write()?;
+        {
+            let mut table = txn.open_table(SALTS_TABLE)?;
+            for key in record.keys() {
+                if table.get(key)?.is_none() {
+                    table.insert(key, value.as_slice())?;
+                }
+            }
+        }
+        txn.commit()?;
+        Ok(())
+    }
+
+    /// Returns true if the given salt type for `match_id` is present in any history file.
+    pub(crate) fn contains(&self, match_id: u64, is_metadata: bool) -> Result<bool, redb::Error> {
+        let key = (match_id, u8::from(!is_metadata));
+        for db in core::iter::once(&self.db).chain(&self.rotated) {
+            let txn = db.begin_read()?;
+            if txn.open_table(SALTS_TABLE)?.get(key)?.is_some() {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Returns every record stored for `match_id`, newest history file first.
+    pub(crate) fn lookup(&self, match_id: u64) -> Result<Vec<HistoryRecord>, redb::Error> {
+        let mut records = Vec::new();
+        for db in core::iter::once(&self.db).chain(&self.rotated) {
+            let txn = db.begin_read()?;
+            let table = txn.open_table(SALTS_TABLE)?;
+            self.read_entries(
+                table.range((match_id, 0)..=(match_id, 1))?,
+                &mut records,
+                usize::MAX,
+            )?;
+        }
+        Ok(records)
+    }
+
+    /// Returns every record in all history files.
+    pub(crate) fn records(&self) -> Result<Vec<HistoryRecord>, redb::Error> {
+        let mut records = Vec::new();
+        for db in core::iter::once(&self.db).chain(&self.rotated) {
+            let txn = db.begin_read()?;
+            self.read_entries(
+                txn.open_table(SALTS_TABLE)?.iter()?,
+                &mut records,
+                usize::MAX,
+            )?;
+        }
+        Ok(records)
+    }
+
+    /// Returns up to `limit` records with the highest match ids, highest first, reading no further
+    /// than needed in each history file.
+    pub(crate) fn recent(&self, limit: usize) -> Result<Vec<HistoryRecord>, redb::Error> {
+        let mut records = Vec::new();
+        for db in core::iter::once(&self.db).chain(&self.rotated) {
+            let txn = db.begin_read()?;
+            let mut file_records = Vec::new();
+            self.read_entries(
+                txn.open_table(SALTS_TABLE)?.iter()?.rev(),
+                &mut file_records,
+                limit,
+            )?;
+            records.append(&mut file_records);
+        }
+        // A record can also be repeated in a rotated file
+        records.sort_by_key(|r| {
+            (
+                core::cmp::Reverse(r.match_id),
+                r.ingested_at,
+                r.metadata_salt,
+                r.replay_salt,
+            )
+        });
+        records.dedup();
+        records.truncate(limit);
+        Ok(records)
+    }
+
+    /// Decodes `entries` into `records` until it holds `limit` records. A record with both salts
+    /// is stored under both of its keys, and only returned once.
+    fn read_entries<'a>(
+        &self,
+        entries: impl Iterator<Item = Result<Entry<'a>, StorageError>>,
+        records: &mut Vec<HistoryRecord>,
+        limit: usize,
+    ) -> Result<(), redb::Error> {
+        let mut previous: Option<(u64, Vec<u8>)> = None;
+        for entry in entries {
+            if records.len() >= limit {
+                break;
+            }
+            let (key, value) = entry?;
+            let (match_id, _) = key.value();
+            let value = value.value();
+            if previous
+                .as_ref()
+                .is_some_and(|(id, previous)| *id == match_id && previous == value)
+            {
+                continue;
+            }
+            records.extend(self.decode(value));
+            previous = Some((match_id, value.to_vec()));
+        }
+        Ok(())
+    }
+
+    fn decode(&self, value: &[u8]) -> Option<HistoryRecord> {
+        let mut record: HistoryRecord = serde_json::from_slice(value).ok()?;
+        if let Some(uploader) = record
+            .uploader
+            .as_deref()
+            .and_then(|u| u.strip_prefix(ENCRYPTED_PREFIX))
+        {
+            record.uploader = self
+                .cipher()
+                .and_then(|cipher| decrypt(&cipher, uploader))
+                .or_else(|| Some("<encrypted>".to_string()));
+        }
+        Some(record)
+    }
+
+    fn needs_rotation(&self) -> bool {
+        std::fs::metadata(&self.path).is_ok_and(|m| m.len() >= MAX_HISTORY_SIZE)
+    }
+
+    /// Moves the active file to `history.1.redb`, shifting older files up and deleting the oldest.
+    /// Moves the active file aside and opens a fresh one. If the files cannot be moved, the
+    /// current ones are reopened instead, so the history stays usable.
+    fn rotate(self) -> Result<Self, redb::Error> {
+        let Self {
+            path,
+            db,
+            rotated,
+            key,
+            encrypt,
+        } = self;
+        drop(db);
+        drop(rotated);
+
+        match rotate_files(&path) {
+            Ok(()) => info!("Rotated history file {}", path.display()),
+            Err(e) => warn!(
+                "Failed to rotate history file {}, keeping it: {e}",
+                path.display()
+            ),
+        }
+        Self::open(&path, key, encrypt)
+    }
+}
+
+/// Renames `path` to its first rotated file, shifting the older ones and dropping the oldest.
+fn rotate_files(path: &Path) -> std::io::Result<()> {
+    let _ = std::fs::remove_file(rotated_path(path, MAX_ROTATED_FILES));
+    for i in (1..MAX_ROTATED_FILES).rev() {
+        let from = rotated_path(path, i);
+        if from.exists() {
+            std::fs::rename(&from, rotated_path(path, i + 1))?;
+        }
+    }
+    std::fs::rename(path, rotated_path(path, 1))
+}
+
+fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    path.with_extension(format!("{index}.redb"))
+}
+
+fn encrypt(cipher: &ChaCha20Poly1305, plaintext: &str) -> Option<String> {
+    let mut nonce = [0u8; 12];
+    getrandom::fill(&mut nonce).ok()?;
+    let ciphertext = cipher
+        .encrypt(&Nonce::from(nonce), plaintext.as_bytes())
+        .ok()?;
+    Some(format!(
+        "{ENCRYPTED_PREFIX}{}{}",
+        hex::encode(nonce),
+        hex::encode(ciphertext)
+    ))
+}
+
+fn decrypt(cipher: &ChaCha20Poly1305, encoded: &str) -> Option<String> {
+    let bytes = hex::decode(encoded).ok()?;
+    let (nonce, ciphertext) = bytes.split_at_checked(12)?;
+    let nonce: [u8; 12] = nonce.try_into().ok()?;
+    let plaintext = cipher.decrypt(&Nonce::from(nonce), ciphertext).ok()?;
+    String::from_utf8(plaintext).ok()
+}
+
+fn read_key(path: &Path) -> Option<[u8; 32]> {
+    let content = std::fs::read_to_string(path).ok()?;
+    let mut key = [0u8; 32];
+    hex::decode_to_slice(content.trim(), &mut key).ok()?;
+    Some(key)
+}
+
+fn load_or_create_key(path: &Path) -> Option<[u8; 32]> {
+    if let Some(key) = read_key(path) {
+        return Some(key);
+    }
+    let mut key = [0u8; 32];
+    if let Err(e) = getrandom::fill(&mut key) {
+        warn!("Failed to generate history encryption key: {e}");
+        return None;
+    }
+    match crate::utils::create_secret_file(path, hex::encode(key).as_bytes()) {
+        Ok(()) => Some(key),
+        // Another process created it in the meantime
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => read_key(path),
+        Err(e) => {
+            warn!(
+                "Failed to persist history encryption key at {}: {e:?}",
+                path.display()
+            );
+            None
+        }
+    }
+}
+
+/// Imports the legacy `fetched-salts.jsonl` log and renames it so it is only imported once.
+fn import_legacy_log(history: &History, path: &Path) {
+    #[derive(Deserialize)]
+    struct LegacySalt {
+        match_id: u64,
+        cluster_id: u32,
+        metadata_salt: Option<u32>,
+        replay_salt: Option<u32>,
+        username: Option<String>,
+    }
+
+    let Ok(content) = std::fs::read_to_string(path) else {
+        return;
+    };
+    let mut imported = 0;
+    for line in content.lines() {
+        let Ok(legacy) = serde_json::from_str::<LegacySalt>(line) else {
+            continue;
+        };
+        let record = HistoryRecord {
+            match_id: legacy.match_id,
+            cluster_id: legacy.cluster_id,
+            metadata_salt: legacy.metadata_salt,
+            replay_salt: legacy.replay_salt,
+            uploader: legacy.username,
+            ingested_at: 0,
+        };
+        match history.insert(&record) {
+            Ok(()) => imported += 1,
+            Err(e) => warn!("Failed to import legacy salt: {e}"),
+        }
+    }
+    info!("Imported {imported} salts from {}", path.display());
+    if let Err(e) = std::fs::rename(path, path.with_extension("jsonl.imported")) {
+        warn!("Failed to rename legacy log file: {e:?}");
+    }
+}
+
+/// Opens the history in the data directory and makes it available to [`record`] and friends.
+/// When `encrypt` is set, uploader identities are encrypted with a key stored next to the history.
+/// That only protects copies of the database made without the key, e.g. in backups or bug
+/// reports: anyone who can read the data directory can read the key as well.
+pub(crate) fn init(encrypt: bool) {
+    let Some(data_dir) = crate::utils::data_dir() else {
+        warn!("Failed to determine data directory, history is disabled");
+        return;
+    };
+    let key_path = data_dir.join(KEY_FILE_NAME);
+    let key = if encrypt {
+        load_or_create_key(&key_path)
+    } else {
+        read_key(&key_path)
+    };
+
+    match History::open(&data_dir.join(HISTORY_FILE_NAME), key, encrypt) {
+        Ok(history) => {
+            import_legacy_log(&history, &data_dir.join(LEGACY_LOG_FILE_NAME));
+            let lock = HISTORY.get_or_init(Default::default);
+            let mut current = lock.write().unwrap_or_else(|poisoned| {
+                warn!("Failed to lock history for writing");
+                poisoned.into_inner()
+            });
+            *current = Some(history);
+        }
+        Err(e) => warn!("Failed to open history: {e}"),
+    }
+}
+
+fn with_history<T>(f: impl FnOnce(&History) -> Result<T, redb::Error>) -> Option<T> {
+    let lock = HISTORY.get()?;
+    let history = lock.read().unwrap_or_else(|poisoned| {
+        warn!("Failed to lock history for reading");
+        poisoned.into_inner()
+    });
+    match f(history.as_ref()?) {
+        Ok(value) => Some(value),
+        Err(e) => {
+            warn!("History operation failed: {e}");
+            None
+        }
+    }
+}
+
+/// Records a successfully ingested salt, rotating the history file if it grew too large.
+pub(crate) fn record(salt: &Salts) {
+    if with_history(|h| h.insert(&HistoryRecord::from_salts(salt))).is_none() {
+        return;
+    }
+
+    let Some(lock) = HISTORY.get() else {
+        return;
+    };
+    let mut history = lock.write().unwrap_or_else(|poisoned| {
+        warn!("Failed to lock history for writing");
+        poisoned.into_inner()
+    });
+    if history.as_ref().is_some_and(History::needs_rotation)
+        && let Some(current) = history.take()
+    {
+        match current.rotate() {
+            Ok(rotated) => *history = Some(rotated),
+            Err(e) => warn!("Failed to reopen history, not recording until restart: {e}"),
+        }
+    }
+}
+
+/// Returns true if the given salt type for `match_id` was ingested before.
+pub(crate) fn contains(match_id: u64, is_metadata: bool) -> bool {
+    with_history(|h| h.contains(match_id, is_metadata)).unwrap_or(false)
+}
+
+/// Answers `query` from the local history, oldest record first, or returns `None` if the history
+/// is not open in this process.
+pub(crate) fn query(query: Query) -> Option<Vec<HistoryRecord>> {
+    let mut records = match query {
+        Query::Match { match_id } => with_history(|h| h.lookup(match_id))?,
+        Query::Recent { limit } => with_history(|h| h.recent(limit))?,
+        Query::All => with_history(History::records)?,
+    };
+    // A record can also be repeated in a rotated file
+    records.sort_by_key(|r| (r.ingested_at, r.match_id, r.metadata_salt, r.replay_salt));
+    records.dedup();
+    Some(records)
+}
+
+/// Answers `query` from the local history, or asks the running instance if there is one, as only
+/// the holder of the instance lock may open the history. `encrypt` is `--encrypt-history`.
+fn query_anywhere(query: Query, encrypt: bool) -> Result<Vec<HistoryRecord>, String> {
+    // Held while reading, so no instance starts and opens the history in the meantime
+    let _lock = match crate::locate_cache_dir() {
+        Ok(cache_dir) => {
+            let Some(lock) = crate::instance_lock::try_acquire(&cache_dir) else {
+                return crate::ipc::query(&cache_dir, query).map_err(|e| {
+                    format!(
+                        "History is not available, and the running instance could not be asked for it: {e}"
+                    )
+                });
+            };
+            Some(lock)
+        }
+        // Nothing can be watching a cache that does not exist
+        Err(e) => {
+            debug!("{e}, reading the history directly");
+            None
+        }
+    };
+    init(encrypt);
+    self::query(query).ok_or_else(|| "History is not available".to_string())
+}
+
+/// Prints the history for `match_id`, or the `limit` records of the most recently played matches,
+/// to stdout.
+pub(crate) fn print_history(
+    match_id: Option<u64>,
+    limit: usize,
+    encrypt: bool,
+) -> Result<(), String> {
+    let records = query_anywhere(
+        match_id.map_or(Query::Recent { limit }, |match_id| Query::Match {
+            match_id,
+        }),
+        encrypt,
+    )?;
+
+    if records.is_empty() {
+        println!("No ingested salts found");
+    }
+    for record in records {
+        let kind = match (record.metadata_salt, record.replay_salt) {
+            (Some(_), Some(_)) => "metadata+replay",
+            (Some(_), None) => "metadata",
+            _ => "replay",
+        };
+        println!(
+            "{}\tcluster {}\t{kind}\tingested_at {}\t{}",
+            record.match_id,
+            record.cluster_id,
+            record.ingested_at,
+            record.uploader.as_deref().unwrap_or("-"),
+        );
+    }
+    Ok(())
+}
+
+/// Writes the whole history as newline-delimited JSON to `output`, or stdout if not given.
+pub(crate) fn export(output: Option<&Path>, encrypt: bool) -> Result<(), String> {
+    let records = query_anywhere(Query::All, encrypt)?;
+    let mut writer: Box<dyn Write> = match output {
+        Some(path) => Box::new(BufWriter::new(
+            std::fs::File::create(path).map_err(|e| format!("{}: {e}", path.display()))?,
+        )),
+        None => Box::new(std::io::stdout().lock()),
+    };
+    for record in &records {
+        let line = serde_json::to_string(record).map_err(|e| e.to_string())?;
+        writeln!(writer, "{line}").map_err(|e| e.to_string())?;
+    }
+    writer.flush().map_err(|e| e.to_string())?;
+    if let Some(path) = output {
+        info!("Exported {} salts to {}", records.len(), path.display());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_history_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "deadlock-history-test-{name}-{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join(HISTORY_FILE_NAME)
+    }
+
+    fn record(match_id: u64, metadata: bool) -> HistoryRecord {
+        HistoryRecord {
+            match_id,
+            cluster_id: 183,
+            metadata_salt: metadata.then_some(1),
+            replay_salt: (!metadata).then_some(2),
+            uploader: Some("ingest-tool:12345".to_string()),
+            ingested_at: 1,
+        }
+    }
+
+    #[test]
+    fn test_insert_and_lookup() {
+        let path = temp_history_path("lookup");
+        let history = History::open(&path, None, false).unwrap();
+
+        history.insert(&record(42, true)).unwrap();
+        assert!(history.contains(42, true).unwrap());
+        assert!(!history.contains(42, false).unwrap());
+        assert!(!history.contains(43, true).unwrap());
+
+        history.insert(&record(42, false)).unwrap();
+        history.insert(&record(43, true)).unwrap();
+        assert_eq!(history.lookup(42).unwrap().len(), 2);
+        assert_eq!(history.records().unwrap().len(), 3);
+
+        let _ = std::fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[test]
+    fn test_record_with_both_salts_is_returned_once() {
+        let path = temp_history_path("dedupe");
+        let history = History::open(&path, None, false).unwrap();
+        let both = HistoryRecord {
+            metadata_salt: Some(1),
+            replay_salt: Some(2),
+            ..record(42, true)
+        };
+        history.insert(&both).unwrap();
+        history.insert(&record(43, true)).unwrap();
+        assert!(history.contains(42, true).unwrap());
+        assert!(history.contains(42, false).unwrap());
+        assert_eq!(history.lookup(42).unwrap(), vec![both]);
+        assert_eq!(history.records().unwrap().len(), 2);
+
+        let _ = std::fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[test]
+    fn test_recent_stops_at_limit() {
+        let path = temp_history_path("recent");
+        let history = History::open(&path, None, false).unwrap();
+        for match_id in 40..45 {
+            history.insert(&record(match_id, true)).unwrap();
+        }
+        let recent: Vec<_> = history
+            .recent(2)
+            .unwrap()
+            .iter()
+            .map(|r| r.match_id)
+            .collect();
+        assert_eq!(recent, vec![44, 43]);
+        assert_eq!(history.recent(10).unwrap().len(), 5);
+
+        // Rotated files are considered as well
+        let history = history.rotate().unwrap();
+        history.insert(&record(30, true)).unwrap();
+        history.insert(&record(50, true)).unwrap();
+        let recent: Vec<_> = history
+            .recent(2)
+            .unwrap()
+            .iter()
+            .map(|r| r.match_id)
+            .collect();
+        assert_eq!(recent, vec![50, 44]);
+
+        let _ = std::fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[test]
+    fn test_encrypted_uploader() {
+        let path = temp_history_path("encrypted");
+        let key = [7u8; 32];
+        let history = History::open(&path, Some(key), true).unwrap();
+        history.insert(&record(42, true)).unwrap();
+        assert_eq!(
+            history.lookup(42).unwrap()[0].uploader.as_deref(),
+            Some("ingest-tool:12345")
+        );
+        drop(history);
+
+        let history = History::open(&path, None, false).unwrap();
+        assert_eq!(
+            history.lookup(42).unwrap()[0].uploader.as_deref(),
+            Some("<encrypted>")
+        );
+
+        let _ = std::fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[test]
+    fn test_rotation_keeps_lookups() {
+        let path = temp_history_path("rotation");
+        let history = History::open(&path, None, false).unwrap();
+        history.insert(&record(42, true)).unwrap();
+
+        let history = history.rotate().unwrap();
+        assert!(rotated_path(&path, 1).exists());
+        history.insert(&record(43, true)).unwrap();
+        assert!(history.contains(42, true).unwrap());
+        assert!(history.contains(43, true).unwrap());
+
+        let _ = std::fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[test]
+    fn test_failed_rotation_keeps_history() {
+        let path = temp_history_path("failed-rotation");
+        let history = History::open(&path, None, false).unwrap();
+        history.insert(&record(42, true)).unwrap();
+
+        // Shifting the first rotated file fails, as it cannot replace a non-empty directory
+        for i in 1..=2 {
+            let dir = rotated_path(&path, i);
+            std::fs::create_dir(&dir).unwrap();
+            std::fs::write(dir.join("file"), "").unwrap();
+        }
+        let history = history.rotate().unwrap();
+        assert!(history.contains(42, true).unwrap());
+        history.insert(&record(43, true)).unwrap();
+        assert!(history.contains(43, true).unwrap());
+
+        let _ = std::fs::remove_dir_all(path.parent().unwrap());
+    }
+}
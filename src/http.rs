@@ -0,0 +1,375 @@
+//! The HTTP client shared by every outbound request (Deadlock API, Statlocker and update checks).
+//!
+//! Timeouts, the proxy and extra root certificates are configured on the command line. Without
+//! `--proxy`, the standard `ALL_PROXY`, `HTTPS_PROXY`, `HTTP_PROXY` and `NO_PROXY` environment
+//! variables are used.
+//!
+//! Requests identify the client as `deadlock-api-ingest/<version> (<os>; <arch>; <install-mode>)`,
+//! so the API team can attribute traffic and tell which builds misbehave. With `--send-install-id`,
+//! requests to the Deadlock API also carry a random id that is stable for this install.
+
+use core::time::Duration;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use tracing::{info, warn};
+use ureq::Proxy;
+use ureq::http::HeaderValue;
+use ureq::tls::{Certificate, PemItem, RootCerts, TlsConfig};
+
+/// Header carrying the per-install id, only sent to the Deadlock API
+const INSTALL_ID_HEADER: &str = "x-install-id";
+
+/// Name of the file holding the per-install id
+const INSTALL_ID_FILE_NAME: &str = "install-id";
+
+pub(crate) const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to wait for the response headers once the request is sent
+pub(crate) const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+static HTTP_CLIENT: OnceLock<ureq::Agent> = OnceLock::new();
+static INSTALL_MODE: OnceLock<InstallMode> = OnceLock::new();
+static INSTALL_ID: OnceLock<Option<String>> = OnceLock::new();
+
+/// Network settings from the command line.
+pub(crate) struct Settings {
+    pub(crate) connect_timeout: Duration,
+    pub(crate) read_timeout: Duration,
+    /// Proxy URL, replacing the proxy from the environment
+    pub(crate) proxy: Option<String>,
+    /// PEM files with root certificates to trust in addition to the built-in ones
+    pub(crate) ca_certs: Vec<PathBuf>,
+}
+
+/// How this process was started, reported in the `User-Agent`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum InstallMode {
+    /// Started by hand or by the autostart entry of the install script
+    #[default]
+    Standalone,
+    /// Wrapping the game as a Steam launch option
+    LaunchOption,
+    /// Running as a systemd service
+    Systemd,
+    /// Running as a Windows service
+    WindowsService,
+    /// Running in the Docker image
+    Docker,
+    /// A one-shot `snapshot` run
+    Snapshot,
+}
+
+impl InstallMode {
+    /// Tells the service managers and container runtimes apart from a plain start.
+    pub(crate) fn detect() -> Self {
+        if std::env::var_os("INVOCATION_ID").is_some() {
+            // Set by systemd for every unit it starts
+            Self::Systemd
+        } else if Path::new("/.dockerenv").exists() {
+            Self::Docker
+        } else {
+            Self::Standalone
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Standalone => "standalone",
+            Self::LaunchOption => "launch-option",
+            Self::Systemd => "systemd",
+            Self::WindowsService => "windows-service",
+            Self::Docker => "docker",
+            Self::Snapshot => "snapshot",
+        }
+    }
+}
+
+/// Sets the install mode reported in the `User-Agent`. Must be called before the first request.
+pub(crate) fn set_install_mode(mode: InstallMode) {
+    if INSTALL_MODE.set(mode).is_err() {
+        warn!("Install mode was already configured");
+    }
+}
+
+/// Sends the per-install id with requests to the Deadlock API.
+pub(crate) fn enable_install_id() {
+    let _ = INSTALL_ID.get_or_init(load_or_create_install_id);
+}
+
+pub(crate) fn user_agent() -> String {
+    format!(
+        "deadlock-api-ingest/{} ({}; {}; {})",
+        env!("CARGO_PKG_VERSION"),
+        std::env::consts::OS,
+        std::env::consts::ARCH,
+        INSTALL_MODE.get().copied().unwrap_or_default().as_str()
+    )
+}
+
+/// Builds the shared client from `settings`. Must be called before the first request, after
+/// [`set_install_mode`].
+pub(crate) fn configure(settings: &Settings) -> Result<(), String> {
+    let proxy = settings
+        .proxy
+        .as_deref()
+        .map(|proxy| Proxy::new(proxy).map_err(|e| format!("Invalid proxy {proxy}: {e}")))
+        .transpose()?;
+    let mut extra_roots = Vec::new();
+    for path in &settings.ca_certs {
+        extra_roots.extend(load_certs(path)?);
+    }
+    let agent = build_agent(settings, proxy, extra_roots);
+    if let Some(proxy) = agent.config().proxy() {
+        info!(
+            "Sending requests through {} proxy {}:{}",
+            proxy.protocol(),
+            proxy.host(),
+            proxy.port()
+        );
+    }
+    if HTTP_CLIENT.set(agent).is_err() {
+        warn!("HTTP client was already configured");
+    }
+    Ok(())
+}
+
+pub(crate) fn client() -> &'static ureq::Agent {
+    HTTP_CLIENT.get_or_init(|| {
+        let settings = Settings {
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+            proxy: None,
+            ca_certs: Vec::new(),
+        };
+        build_agent(&settings, None, Vec::new())
+    })
+}
+
+/// Builds an agent from `settings`, with the already parsed `proxy` and certificates.
+fn build_agent(
+    settings: &Settings,
+    proxy: Option<Proxy>,
+    extra_roots: Vec<Certificate<'static>>,
+) -> ureq::Agent {
+    let mut config = ureq::Agent::config_builder()
+        .user_agent(user_agent())
+        .timeout_connect(Some(settings.connect_timeout))
+        .timeout_recv_response(Some(settings.read_timeout))
+        // Bounds every API call, so a stalled one can't block the watcher past the systemd
+        // watchdog. Downloads of release binaries set a longer timeout themselves.
+        .timeout_recv_body(Some(settings.read_timeout))
+        // Error statuses are turned into errors by `check_api_response` instead, after the
+        // kill switch has seen their headers
+        .http_status_as_error(false)
+        .middleware(add_install_id)
+        .middleware(check_api_response);
+    if proxy.is_some() {
+        config = config.proxy(proxy);
+    }
+    if !extra_roots.is_empty() {
+        config = config.tls_config(
+            TlsConfig::builder()
+                .root_certs(root_certs(extra_roots))
+                .build(),
+        );
+    }
+    config.build().new_agent()
+}
+
+/// Returns the built-in Mozilla root certificates together with `extra_roots`.
+fn root_certs(extra_roots: Vec<Certificate<'static>>) -> RootCerts {
+    webpki_root_certs::TLS_SERVER_ROOT_CERTS
+        .iter()
+        .map(|der| Certificate::from_der(der).to_owned())
+        .chain(extra_roots)
+        .into()
+}
+
+/// Reads the certificates from a PEM file.
+fn load_certs(path: &Path) -> Result<Vec<Certificate<'static>>, String> {
+    let pem = std::fs::read(path)
+        .map_err(|e| format!("Failed to read CA certificate {}: {e}", path.display()))?;
+    let mut certs = Vec::new();
+    for item in ureq::tls::parse_pem(&pem) {
+        match item {
+            Ok(PemItem::Certificate(cert)) => certs.push(cert),
+            Ok(_) => {}
+            Err(e) => {
+                return Err(format!(
+                    "Failed to parse CA certificate {}: {e}",
+                    path.display()
+                ));
+            }
+        }
+    }
+    if certs.is_empty() {
+        return Err(format!("No certificates found in {}", path.display()));
+    }
+    Ok(certs)
+}
+
+fn add_install_id(
+    mut request: ureq::http::Request<ureq::SendBody>,
+    next: ureq::middleware::MiddlewareNext,
+) -> Result<ureq::http::Response<ureq::Body>, ureq::Error> {
+    if let Some(Some(id)) = INSTALL_ID.get()
+        && is_api_request(&request.uri().to_string(), crate::utils::api_url())
+        && let Ok(value) = HeaderValue::from_str(id)
+    {
+        request.headers_mut().insert(INSTALL_ID_HEADER, value);
+    }
+    next.handle(request)
+}
+
+/// Lets the kill switch see every response of the Deadlock API, including errors such as
+/// `426 Upgrade Required`, then turns error statuses into [`ureq::Error::StatusCode`] like ureq
+/// does by default.
+fn check_api_response(
+    request: ureq::http::Request<ureq::SendBody>,
+    next: ureq::middleware::MiddlewareNext,
+) -> Result<ureq::http::Response<ureq::Body>, ureq::Error> {
+    let is_api = is_api_request(&request.uri().to_string(), crate::utils::api_url());
+    let response = next.handle(request)?;
+    if is_api {
+        crate::kill_switch::observe(&response);
+    }
+    let status = response.status();
+    if status.is_client_error() || status.is_server_error() {
+        return Err(ureq::Error::StatusCode(status.as_u16()));
+    }
+    Ok(response)
+}
+
+/// Returns true if `uri` points at the Deadlock API, rather than e.g. Statlocker or GitHub.
+fn is_api_request(uri: &str, api_url: &str) -> bool {
+    uri.strip_prefix(api_url)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with(['/', '?']))
+}
+
+fn load_or_create_install_id() -> Option<String> {
+    let path = crate::utils::data_dir()?.join(INSTALL_ID_FILE_NAME);
+    if let Some(id) = read_install_id(&path) {
+        return Some(id);
+    }
+
+    let mut id = [0u8; 16];
+    if let Err(e) = getrandom::fill(&mut id) {
+        warn!("Failed to generate install id: {e}");
+        return None;
+    }
+    let id = hex::encode(id);
+    match crate::utils::create_secret_file(&path, id.as_bytes()) {
+        Ok(()) => Some(id),
+        // Another process created it in the meantime
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => read_install_id(&path),
+        Err(e) => {
+            warn!("Failed to persist install id at {}: {e:?}", path.display());
+            None
+        }
+    }
+}
+
+fn read_install_id(path: &Path) -> Option<String> {
+    let content = std::fs::read_to_string(path).ok()?;
+    let id = content.trim();
+    hex::decode(id)
+        .is_ok_and(|id| id.len() == 16)
+        .then(|| id.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{MockResponse, MockServer};
+
+    #[test]
+    fn test_user_agent() {
+        let user_agent = user_agent();
+        assert!(user_agent.starts_with(concat!(
+            "deadlock-api-ingest/",
+            env!("CARGO_PKG_VERSION"),
+            " ("
+        )));
+        assert!(user_agent.contains(std::env::consts::OS));
+
+        let server = MockServer::start(vec![MockResponse::json(200, "{}")]);
+        client().get(&server.url).call().unwrap();
+        let requests = server.requests();
+        assert!(requests[0].headers.iter().any(|(name, value)| {
+            name.eq_ignore_ascii_case("user-agent") && *value == user_agent
+        }));
+    }
+
+    #[test]
+    fn test_load_certs() {
+        let dir = std::env::temp_dir().join(format!("deadlock-ca-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let bundle = dir.join("bundle.pem");
+        std::fs::write(
+            &bundle,
+            "-----BEGIN CERTIFICATE-----\nAAEC\n-----END CERTIFICATE-----\n\
+             -----BEGIN CERTIFICATE-----\nAwQF\n-----END CERTIFICATE-----\n",
+        )
+        .unwrap();
+        let certs = load_certs(&bundle).unwrap();
+        assert_eq!(certs.len(), 2);
+        assert_eq!(certs[0].der(), [0, 1, 2]);
+
+        let RootCerts::Specific(roots) = root_certs(certs) else {
+            panic!("expected specific root certificates");
+        };
+        assert_eq!(
+            roots.len(),
+            webpki_root_certs::TLS_SERVER_ROOT_CERTS.len() + 2
+        );
+
+        let empty = dir.join("empty.pem");
+        std::fs::write(&empty, "not a certificate").unwrap();
+        assert!(load_certs(&empty).is_err());
+        assert!(load_certs(&dir.join("missing.pem")).is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_read_timeout() {
+        let server = MockServer::start(vec![MockResponse::json(200, "{}").delayed(500)]);
+        let settings = Settings {
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            read_timeout: Duration::from_millis(100),
+            proxy: None,
+            ca_certs: Vec::new(),
+        };
+        let agent = build_agent(&settings, None, Vec::new());
+        assert!(matches!(
+            agent.get(&server.url).call(),
+            Err(ureq::Error::Timeout(_))
+        ));
+    }
+
+    #[test]
+    fn test_error_status() {
+        // Turned into errors by the middleware rather than by ureq
+        let server = MockServer::start(vec![MockResponse::json(426, "{}")]);
+        assert!(matches!(
+            client().get(&server.url).call(),
+            Err(ureq::Error::StatusCode(426))
+        ));
+    }
+
+    #[test]
+    fn test_is_api_request() {
+        let api_url = "https://api.deadlock-api.com";
+        assert!(is_api_request(
+            "https://api.deadlock-api.com/v1/matches/salts",
+            api_url
+        ));
+        assert!(is_api_request("https://api.deadlock-api.com", api_url));
+        assert!(!is_api_request(
+            "https://api.deadlock-api.com.example.com/v1",
+            api_url
+        ));
+        assert!(!is_api_request(
+            "https://statlocker.gg/api/match/1/populate",
+            api_url
+        ));
+    }
+}
@@ -1,126 +1,167 @@
+use crate::history;
 use crate::utils::Salts;
-use std::collections::HashMap;
-use std::fs::OpenOptions;
-use std::io::Write;
-use std::path::PathBuf;
-use std::sync::{OnceLock, RwLock};
+use core::fmt::{Display, Formatter};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Mutex, OnceLock};
 use tracing::warn;
 
-/// Maximum size for the log file before truncating (1GB)
-const MAX_LOG_SIZE: u64 = 1_073_741_824;
-
-/// Name of the log file
-const LOG_FILE_NAME: &str = "fetched-salts.jsonl";
+/// Maximum number of matches kept in the cache before the least recently used ones are evicted
+const MAX_ENTRIES: usize = 10_000;
 
 /// Global cache to track successfully ingested salts.
-/// Key is the `match_id`, value is a tuple of `(has_metadata, has_replay)`.
-static INGESTION_CACHE: OnceLock<RwLock<HashMap<u64, (bool, bool)>>> = OnceLock::new();
-
-fn get_log_file_path() -> Option<PathBuf> {
-    // Use platform-specific data directory
-    // Linux: ~/.local/share/deadlock-api-ingest/
-    // macOS: ~/Library/Application Support/deadlock-api-ingest/
-    // Windows: C:\Users\<User>\AppData\Roaming\deadlock-api-ingest\
-    let data_dir = dirs::data_dir()?.join("deadlock-api-ingest");
-
-    // Create directory if it doesn't exist
-    if let Err(e) = std::fs::create_dir_all(&data_dir) {
-        warn!(
-            "Failed to create data directory at {}: {e:?}",
-            data_dir.display()
-        );
-        return None;
+static INGESTION_CACHE: OnceLock<Mutex<IngestionCache>> = OnceLock::new();
+
+/// Hit/miss statistics of the ingestion cache.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct CacheStats {
+    pub(crate) entries: usize,
+    pub(crate) hits: u64,
+    pub(crate) misses: u64,
+    pub(crate) evictions: u64,
+}
+
+impl Display for CacheStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{} entries, {} hits, {} misses, {} evictions",
+            self.entries, self.hits, self.misses, self.evictions
+        )
     }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    has_metadata: bool,
+    has_replay: bool,
+    last_used: u64,
+}
 
-    Some(data_dir.join(LOG_FILE_NAME))
+/// Bounded LRU cache keyed by `match_id`.
+/// `recency` maps the tick of the last access to the `match_id`, so the oldest entry is always first.
+#[derive(Debug)]
+struct IngestionCache {
+    capacity: usize,
+    entries: HashMap<u64, Entry>,
+    recency: BTreeMap<u64, u64>,
+    tick: u64,
+    stats: CacheStats,
 }
 
-fn append_to_log_file(salt: &Salts) {
-    let Some(log_path) = get_log_file_path() else {
-        warn!("Failed to determine log file path");
-        return;
-    };
-
-    // Check file size and truncate if it exceeds 1GB
-    if let Ok(metadata) = std::fs::metadata(&log_path)
-        && metadata.len() >= MAX_LOG_SIZE
-        && let Err(e) = std::fs::remove_file(&log_path)
-    {
-        warn!("Failed to truncate log file: {e:?}");
+impl Default for IngestionCache {
+    fn default() -> Self {
+        Self::with_capacity(MAX_ENTRIES)
     }
+}
 
-    // Serialize the salt to JSON
-    let json_line = match serde_json::to_string(salt) {
-        Ok(json) => json,
-        Err(e) => {
-            warn!("Failed to serialize salt to JSON: {e:?}");
-            return;
+impl IngestionCache {
+    fn with_capacity(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            tick: 0,
+            stats: CacheStats::default(),
         }
-    };
+    }
 
-    // Open the file in append mode (create if it doesn't exist)
-    let mut file = match OpenOptions::new().create(true).append(true).open(&log_path) {
-        Ok(f) => f,
-        Err(e) => {
-            warn!("Failed to open log file at {}: {e:?}", log_path.display());
+    fn touch(&mut self, match_id: u64) -> Option<&mut Entry> {
+        self.tick += 1;
+        let entry = self.entries.get_mut(&match_id)?;
+        self.recency.remove(&entry.last_used);
+        self.recency.insert(self.tick, match_id);
+        entry.last_used = self.tick;
+        Some(entry)
+    }
+
+    fn insert(&mut self, match_id: u64, has_metadata: bool, has_replay: bool) {
+        if let Some(entry) = self.touch(match_id) {
+            entry.has_metadata |= has_metadata;
+            entry.has_replay |= has_replay;
             return;
         }
-    };
 
-    // Write the JSON line followed by a newline
-    if let Err(e) = writeln!(file, "{json_line}") {
-        warn!("Failed to write to log file: {e:?}");
+        while self.entries.len() >= self.capacity {
+            let Some((_, oldest)) = self.recency.pop_first() else {
+                break;
+            };
+            self.entries.remove(&oldest);
+            self.stats.evictions += 1;
+        }
+        self.entries.insert(
+            match_id,
+            Entry {
+                has_metadata,
+                has_replay,
+                last_used: self.tick,
+            },
+        );
+        self.recency.insert(self.tick, match_id);
     }
-}
 
-/// Mark a salt as successfully ingested.
-/// This should only be called after successful ingestion.
-pub(crate) fn mark_ingested(salt: &Salts) {
-    append_to_log_file(salt);
-
-    let cache = INGESTION_CACHE.get_or_init(Default::default);
-    let mut cache = cache.write().unwrap_or_else(|poisoned| {
-        warn!("Failed to lock ingestion cache for writing");
-        poisoned.into_inner()
-    });
-
-    cache
-        .entry(salt.match_id)
-        .and_modify(|entry| {
-            if salt.metadata_salt.is_some() {
-                entry.0 = true;
+    fn get(&mut self, match_id: u64, is_metadata: bool) -> bool {
+        let found = self.touch(match_id).is_some_and(|entry| {
+            if is_metadata {
+                entry.has_metadata
+            } else {
+                entry.has_replay
             }
-            if salt.replay_salt.is_some() {
-                entry.1 = true;
-            }
-        })
-        .or_insert((salt.metadata_salt.is_some(), salt.replay_salt.is_some()));
+        });
+        if found {
+            self.stats.hits += 1;
+        } else {
+            self.stats.misses += 1;
+        }
+        found
+    }
 
-    // Prevent unbounded growth - clear cache if it gets too large
-    if cache.len() > 10_000 {
-        cache.clear();
+    fn stats(&self) -> CacheStats {
+        CacheStats {
+            entries: self.entries.len(),
+            ..self.stats
+        }
     }
 }
 
-/// Check if a salt has already been ingested.
-/// Returns true if the specific salt type (metadata or replay) has been ingested for this `match_id`.
-pub(crate) fn is_ingested(match_id: u64, is_metadata: bool) -> bool {
+fn with_cache<T>(f: impl FnOnce(&mut IngestionCache) -> T) -> T {
     let cache = INGESTION_CACHE.get_or_init(Default::default);
-    let cache = cache.read().unwrap_or_else(|poisoned| {
-        warn!("Failed to lock ingestion cache for reading");
+    let mut cache = cache.lock().unwrap_or_else(|poisoned| {
+        warn!("Failed to lock ingestion cache");
         poisoned.into_inner()
     });
+    f(&mut cache)
+}
 
-    if let Some(entry) = cache.get(&match_id) {
-        let (has_metadata, has_replay) = *entry;
-        if is_metadata {
-            has_metadata
-        } else {
-            has_replay
-        }
-    } else {
-        false
+/// Mark a salt as successfully ingested.
+/// This should only be called after successful ingestion.
+pub(crate) fn mark_ingested(salt: &Salts) {
+    history::record(salt);
+    with_cache(|cache| {
+        cache.insert(
+            salt.match_id,
+            salt.metadata_salt.is_some(),
+            salt.replay_salt.is_some(),
+        );
+    });
+}
+
+/// Check if a salt has already been ingested.
+/// Returns true if the specific salt type (metadata or replay) has been ingested for this `match_id`,
+/// falling back to the local history for matches that are not in the in-memory cache.
+pub(crate) fn is_ingested(match_id: u64, is_metadata: bool) -> bool {
+    if with_cache(|cache| cache.get(match_id, is_metadata)) {
+        return true;
     }
+    if history::contains(match_id, is_metadata) {
+        with_cache(|cache| cache.insert(match_id, is_metadata, !is_metadata));
+        return true;
+    }
+    false
+}
+
+/// Returns the current hit/miss statistics of the ingestion cache.
+pub(crate) fn stats() -> CacheStats {
+    with_cache(|cache| cache.stats())
 }
 
 #[cfg(test)]
@@ -157,4 +198,36 @@ mod tests {
         assert!(is_ingested(match_id, true));
         assert!(is_ingested(match_id, false));
     }
+
+    #[test]
+    fn test_lru_eviction() {
+        let mut cache = IngestionCache::with_capacity(2);
+        cache.insert(1, true, false);
+        cache.insert(2, true, false);
+
+        // Touch 1 so that 2 becomes the least recently used entry
+        assert!(cache.get(1, true));
+        cache.insert(3, false, true);
+
+        assert!(cache.get(1, true));
+        assert!(!cache.get(2, true));
+        assert!(cache.get(3, false));
+        assert!(!cache.get(3, true));
+
+        let stats = cache.stats();
+        assert_eq!(stats.entries, 2);
+        assert_eq!(stats.evictions, 1);
+        assert_eq!(stats.hits, 3);
+        assert_eq!(stats.misses, 2);
+    }
+
+    #[test]
+    fn test_insert_merges_salt_types() {
+        let mut cache = IngestionCache::with_capacity(2);
+        cache.insert(1, true, false);
+        cache.insert(1, false, true);
+        assert!(cache.get(1, true));
+        assert!(cache.get(1, false));
+        assert_eq!(cache.stats().entries, 1);
+    }
 }
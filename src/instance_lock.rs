@@ -0,0 +1,159 @@
+use crate::shutdown;
+use core::time::Duration;
+use sha2::{Digest, Sha256};
+use std::fs::{File, TryLockError};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+/// How often a waiting instance checks whether the running one has exited
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Exclusive lock on a Steam cache directory, held for as long as this value lives.
+///
+/// Only one instance may watch a cache directory at a time, otherwise every salt is uploaded twice
+/// (e.g. by the background service and by the Steam launch option wrapper).
+/// The operating system releases the lock when the process exits, even after a crash.
+pub(crate) struct InstanceLock {
+    /// `None` if the lock could not be taken at all (e.g. no data directory),
+    /// in which case this instance runs without it rather than not at all.
+    _file: Option<File>,
+}
+
+impl InstanceLock {
+    /// Returns `Ok(None)` if another process already holds the lock.
+    fn try_acquire_at(path: &Path) -> std::io::Result<Option<Self>> {
+        let mut file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => return Ok(None),
+            Err(TryLockError::Error(e)) => return Err(e),
+        }
+        // Informational only, to help finding the other instance
+        file.set_len(0)?;
+        writeln!(file, "{}", std::process::id())?;
+        Ok(Some(Self { _file: Some(file) }))
+    }
+}
+
+/// Per-cache-directory file in the shared directory, e.g. `instance-<hash>.lock`,
+/// so instances watching different Steam installations do not interfere.
+pub(crate) fn instance_file(cache_dir: &Path, extension: &str) -> Option<PathBuf> {
+    let cache_dir = cache_dir
+        .canonicalize()
+        .unwrap_or_else(|_| cache_dir.to_path_buf());
+    let digest = Sha256::digest(cache_dir.as_os_str().as_encoded_bytes());
+    let name = format!("instance-{}.{extension}", hex::encode(&digest[..8]));
+    Some(crate::utils::shared_dir()?.join(name))
+}
+
+/// Tries to become the only instance watching `cache_dir`.
+/// Returns `None` if another instance already watches it.
+pub(crate) fn try_acquire(cache_dir: &Path) -> Option<InstanceLock> {
+    let Some(path) = instance_file(cache_dir, "lock") else {
+        warn!("Failed to determine shared directory, running without the single-instance lock");
+        return Some(InstanceLock { _file: None });
+    };
+    InstanceLock::try_acquire_at(&path).unwrap_or_else(|e| {
+        warn!(
+            "Failed to take the instance lock at {}, running without it: {e}",
+            path.display()
+        );
+        Some(InstanceLock { _file: None })
+    })
+}
+
+/// Waits until no other instance watches `cache_dir` and takes over.
+/// Returns `None` if shutdown was requested while waiting.
+pub(crate) fn wait_for(cache_dir: &Path) -> Option<InstanceLock> {
+    info!("Another instance is already watching the Steam cache, waiting for it to exit");
+    // Waiting is the expected state of this instance, not a startup that takes too long
+    crate::systemd::ready("Waiting for another instance to exit");
+    let lock = wait_with(|| try_acquire(cache_dir), RETRY_INTERVAL)?;
+    info!("The other instance exited, taking over");
+    Some(lock)
+}
+
+/// Calls `acquire` every `interval` until it returns the lock, or shutdown is requested.
+fn wait_with(
+    mut acquire: impl FnMut() -> Option<InstanceLock>,
+    interval: Duration,
+) -> Option<InstanceLock> {
+    loop {
+        crate::systemd::watchdog();
+        if let Some(lock) = acquire() {
+            return Some(lock);
+        }
+        if shutdown::wait_timeout(interval) {
+            return None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lock_is_exclusive() {
+        let path = std::env::temp_dir().join(format!(
+            "deadlock-instance-test-{}.lock",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+
+        let first = InstanceLock::try_acquire_at(&path).unwrap();
+        assert!(first.is_some());
+        assert!(InstanceLock::try_acquire_at(&path).unwrap().is_none());
+
+        drop(first);
+        assert!(InstanceLock::try_acquire_at(&path).unwrap().is_some());
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_takeover_opens_stores_after_release() {
+        // Waiting ends early once a shutdown was requested
+        let _guard = shutdown::test_guard();
+        let dir = std::env::temp_dir().join(format!(
+            "deadlock-instance-takeover-test-{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let lock_path = dir.join("instance.lock");
+        let outbox_path = dir.join("outbox.redb");
+
+        // The running instance holds the lock and has the outbox open
+        let first = InstanceLock::try_acquire_at(&lock_path).unwrap().unwrap();
+        let outbox = crate::outbox::Outbox::open(&outbox_path).unwrap();
+        assert!(crate::outbox::Outbox::open(&outbox_path).is_err());
+        let running = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(200));
+            drop(outbox);
+            drop(first);
+        });
+
+        let attempts = core::cell::Cell::new(0);
+        let lock = wait_with(
+            || {
+                attempts.set(attempts.get() + 1);
+                InstanceLock::try_acquire_at(&lock_path).unwrap()
+            },
+            Duration::from_millis(20),
+        );
+        assert!(lock.is_some());
+        assert!(attempts.get() > 1);
+        running.join().unwrap();
+        // Opened only once the lock is held, so the store is free again
+        assert!(crate::outbox::Outbox::open(&outbox_path).is_ok());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
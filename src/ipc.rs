@@ -0,0 +1,305 @@
+//! Local channel between the launch wrapper and an instance that is already watching the cache.
+//!
+//! The watching instance listens on a random localhost port and writes the port together with a
+//! random token to `instance-<hash>.ipc` in the shared directory, which is reachable from every
+//! account (see [`crate::utils::shared_dir`]). A wrapper that finds the cache already being watched
+//! reports the game starting and stopping there, instead of running a watcher of its own. The
+//! `history` and `export` commands ask it for the history over the same channel, as only one
+//! process can open the history at a time. Each connection carries a single JSON line, answered
+//! with `ok` (followed by the records of a history query, one JSON line each).
+
+use crate::history::{HistoryRecord, Query};
+use crate::instance_lock;
+use core::net::{Ipv4Addr, SocketAddr};
+use core::time::Duration;
+use serde::{Deserialize, Serialize};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{debug, info, warn};
+
+/// How long to wait for the other instance to accept and answer
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Something the launch wrapper reports to the watching instance.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub(crate) enum Event {
+    GameStarted,
+    /// The game exited. `launched_at` is the launch time in seconds since the Unix epoch,
+    /// so the watching instance knows which cache files to scan again.
+    GameStopped {
+        launched_at: u64,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+struct Endpoint {
+    port: u16,
+    token: String,
+}
+
+/// What a connection asks for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+enum Message {
+    Event(Event),
+    Query(Query),
+}
+
+#[derive(Serialize, Deserialize)]
+struct Request {
+    token: String,
+    #[serde(flatten)]
+    message: Message,
+}
+
+fn endpoint_path(cache_dir: &Path) -> Option<PathBuf> {
+    instance_lock::instance_file(cache_dir, "ipc")
+}
+
+/// Starts listening for the launch wrapper on behalf of the instance watching `cache_dir`.
+pub(crate) fn serve(cache_dir: &Path) {
+    let Some(path) = endpoint_path(cache_dir) else {
+        warn!(
+            "Failed to determine shared directory, the launch wrapper cannot hand off to this instance"
+        );
+        return;
+    };
+    let listener = match TcpListener::bind((Ipv4Addr::LOCALHOST, 0)) {
+        Ok(listener) => listener,
+        Err(e) => {
+            warn!("Failed to listen for the launch wrapper: {e}");
+            return;
+        }
+    };
+    let mut token = [0u8; 16];
+    if let Err(e) = getrandom::fill(&mut token) {
+        warn!("Failed to generate IPC token: {e}");
+        return;
+    }
+    let endpoint = Endpoint {
+        port: listener.local_addr().map_or(0, |addr| addr.port()),
+        token: hex::encode(token),
+    };
+    // Left behind by an instance that exited, as only the holder of the instance lock gets here.
+    // Created anew rather than overwritten, so the token is never written through a file or
+    // link someone else planted in the shared directory.
+    let _ = std::fs::remove_file(&path);
+    let written = serde_json::to_vec(&endpoint)
+        .map_err(io::Error::other)
+        .and_then(|content| crate::utils::create_secret_file(&path, &content));
+    if let Err(e) = written {
+        warn!("Failed to write IPC endpoint to {}: {e}", path.display());
+        return;
+    }
+    debug!("Listening for the launch wrapper on port {}", endpoint.port);
+
+    let spawned = std::thread::Builder::new()
+        .name("ipc-server".into())
+        .spawn(move || {
+            listen(&listener, &endpoint.token, handle, crate::history::query);
+        });
+    if let Err(e) = spawned {
+        warn!("Failed to spawn IPC thread: {e}");
+    }
+}
+
+fn handle(event: Event) {
+    match event {
+        Event::GameStarted => info!("The launch wrapper reported that the game started"),
+        Event::GameStopped { launched_at } => {
+            info!("The launch wrapper reported that the game stopped, scanning for new salts");
+            // The watcher runs the scan, so salts are not ingested by two threads at once
+            crate::scan_cache::request_rescan(UNIX_EPOCH + Duration::from_secs(launched_at));
+        }
+    }
+}
+
+fn listen(
+    listener: &TcpListener,
+    token: &str,
+    on_event: impl Fn(Event),
+    on_query: impl Fn(Query) -> Option<Vec<HistoryRecord>>,
+) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                debug!("Failed to accept IPC connection: {e}");
+                continue;
+            }
+        };
+        match read_request(&stream, token) {
+            // Acknowledged before the event is handled, so the wrapper does not wait for the rescan
+            Ok(Message::Event(event)) => match writeln!(&stream, "ok") {
+                Ok(()) => on_event(event),
+                Err(e) => debug!("Failed to acknowledge IPC request: {e}"),
+            },
+            Ok(Message::Query(query)) => {
+                if let Err(e) = answer(&stream, on_query(query)) {
+                    debug!("Failed to answer history query: {e}");
+                }
+            }
+            Err(e) => warn!("Rejected IPC request: {e}"),
+        }
+    }
+}
+
+/// Sends the records answering a history query, one JSON line each after `ok`, or `unavailable`
+/// if this instance has no history either.
+fn answer(stream: &TcpStream, records: Option<Vec<HistoryRecord>>) -> io::Result<()> {
+    let mut writer = BufWriter::new(stream);
+    let Some(records) = records else {
+        writeln!(writer, "unavailable")?;
+        return writer.flush();
+    };
+    writeln!(writer, "ok")?;
+    for record in &records {
+        serde_json::to_writer(&mut writer, record)?;
+        writeln!(writer)?;
+    }
+    writer.flush()
+}
+
+/// Reads a single request and checks its token.
+fn read_request(stream: &TcpStream, token: &str) -> io::Result<Message> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    let request: Request = serde_json::from_str(&line).map_err(io::Error::other)?;
+    if request.token != token {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "invalid token",
+        ));
+    }
+    Ok(request.message)
+}
+
+/// Sends `message` and returns the reply, after checking that its first line is `ok`.
+fn request(endpoint: &Endpoint, message: Message) -> io::Result<io::Lines<BufReader<TcpStream>>> {
+    let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, endpoint.port));
+    let mut stream = TcpStream::connect_timeout(&addr, TIMEOUT)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    let mut request = serde_json::to_vec(&Request {
+        token: endpoint.token.clone(),
+        message,
+    })
+    .map_err(io::Error::other)?;
+    request.push(b'\n');
+    stream.write_all(&request)?;
+
+    let mut lines = BufReader::new(stream).lines();
+    match lines.next().transpose()?.as_deref().map(str::trim) {
+        Some("ok") => Ok(lines),
+        Some("unavailable") => Err(io::Error::other("the running instance has no history")),
+        _ => Err(io::Error::other("request was not acknowledged")),
+    }
+}
+
+fn send(endpoint: &Endpoint, event: Event) -> io::Result<()> {
+    request(endpoint, Message::Event(event)).map(|_| ())
+}
+
+fn ask(endpoint: &Endpoint, query: Query) -> io::Result<Vec<HistoryRecord>> {
+    request(endpoint, Message::Query(query))?
+        .map(|line| serde_json::from_str(&line?).map_err(io::Error::other))
+        .collect()
+}
+
+fn read_endpoint(cache_dir: &Path) -> io::Result<Endpoint> {
+    let path = endpoint_path(cache_dir)
+        .ok_or_else(|| io::Error::other("failed to determine shared directory"))?;
+    serde_json::from_slice(&std::fs::read(path)?).map_err(io::Error::other)
+}
+
+/// Reports `event` to the instance watching `cache_dir`.
+pub(crate) fn notify(cache_dir: &Path, event: Event) -> io::Result<()> {
+    send(&read_endpoint(cache_dir)?, event)
+}
+
+/// Asks the instance watching `cache_dir` for records from its history.
+pub(crate) fn query(cache_dir: &Path, query: Query) -> io::Result<Vec<HistoryRecord>> {
+    ask(&read_endpoint(cache_dir)?, query)
+}
+
+/// Seconds since the Unix epoch, as sent in [`Event::GameStopped`].
+pub(crate) fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_request_roundtrip() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let endpoint = Endpoint {
+            port: listener.local_addr().unwrap().port(),
+            token: "secret".to_string(),
+        };
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            listen(
+                &listener,
+                "secret",
+                |event| tx.send(event).unwrap(),
+                |_| None,
+            );
+        });
+
+        send(&endpoint, Event::GameStarted).unwrap();
+        send(&endpoint, Event::GameStopped { launched_at: 42 }).unwrap();
+        assert_eq!(rx.recv().unwrap(), Event::GameStarted);
+        assert_eq!(rx.recv().unwrap(), Event::GameStopped { launched_at: 42 });
+
+        let wrong_token = Endpoint {
+            token: "guess".to_string(),
+            ..endpoint
+        };
+        assert!(send(&wrong_token, Event::GameStarted).is_err());
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_query_roundtrip() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let endpoint = Endpoint {
+            port: listener.local_addr().unwrap().port(),
+            token: "secret".to_string(),
+        };
+        let record = HistoryRecord {
+            match_id: 42,
+            cluster_id: 183,
+            metadata_salt: Some(1),
+            replay_salt: None,
+            uploader: None,
+            ingested_at: 1,
+        };
+        let answer = vec![record.clone(), record];
+        let expected = answer.clone();
+        std::thread::spawn(move || {
+            listen(
+                &listener,
+                "secret",
+                |_| panic!("a query is not an event"),
+                |query| (query != Query::All).then(|| answer.clone()),
+            );
+        });
+
+        assert_eq!(
+            ask(&endpoint, Query::Match { match_id: 42 }).unwrap(),
+            expected
+        );
+        assert_eq!(
+            ask(&endpoint, Query::Recent { limit: 10 }).unwrap(),
+            expected
+        );
+        assert!(ask(&endpoint, Query::All).is_err());
+    }
+}
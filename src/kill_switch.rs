@@ -0,0 +1,136 @@
+//! Lets the Deadlock API stop uploads from client versions that are known to send bad data.
+//!
+//! Every request carries the client version in its `User-Agent`, set by the shared client in
+//! [`crate::http`]. The API disables a version by answering with `426 Upgrade Required`, or with
+//! an `X-Min-Client-Version` header naming the oldest version it accepts. Every response from the
+//! API is checked, including errors, by a middleware of the shared client. Uploads are then paused,
+//! keeping the salts in the outbox for the updated version, and a single upload is attempted again
+//! once an hour in case this version is accepted again.
+
+use core::time::Duration;
+use std::sync::Mutex;
+use std::time::Instant;
+use tracing::{error, info, warn};
+
+/// Response header with the oldest client version the API accepts
+const MIN_VERSION_HEADER: &str = "x-min-client-version";
+
+/// How long uploads stay paused before the API is asked again
+const RETRY_INTERVAL: Duration = Duration::from_hours(1);
+
+const RELEASES_URL: &str = "https://github.com/deadlock-api/deadlock-api-ingest/releases/latest";
+
+static STATE: Mutex<KillSwitch> = Mutex::new(KillSwitch { paused: None });
+
+struct KillSwitch {
+    /// When uploads were last refused, and why
+    paused: Option<(Instant, String)>,
+}
+
+impl KillSwitch {
+    /// Updates the state from a response, returning true if it changed.
+    fn observe(&mut self, status: u16, min_version: Option<&str>, now: Instant) -> bool {
+        let refusal = if status == 426 {
+            Some("the Deadlock API requires a newer version".to_string())
+        } else if let Some(min_version) =
+            min_version.filter(|min| crate::update::is_newer(min, env!("CARGO_PKG_VERSION")))
+        {
+            Some(format!(
+                "the Deadlock API requires version {min_version} or newer"
+            ))
+        } else if (200..300).contains(&status) {
+            None
+        } else {
+            // Other errors say nothing about whether this version is accepted
+            return false;
+        };
+        let changed = self.paused.is_some() != refusal.is_some();
+        self.paused = refusal.map(|reason| (now, reason));
+        changed
+    }
+
+    /// Returns why uploads are paused. Once the retry interval has passed, the first caller gets
+    /// `None` and the interval starts over, so only one upload asks the API again.
+    fn check(&mut self, now: Instant) -> Option<&str> {
+        let (since, reason) = self.paused.as_mut()?;
+        if now.duration_since(*since) >= RETRY_INTERVAL {
+            *since = now;
+            return None;
+        }
+        Some(reason)
+    }
+}
+
+fn state() -> std::sync::MutexGuard<'static, KillSwitch> {
+    STATE.lock().unwrap_or_else(|poisoned| {
+        warn!("Failed to lock kill switch state");
+        poisoned.into_inner()
+    })
+}
+
+/// Checks a response of the Deadlock API for the version being refused (or accepted again).
+pub(crate) fn observe(response: &ureq::http::Response<ureq::Body>) {
+    let min_version = response
+        .headers()
+        .get(MIN_VERSION_HEADER)
+        .and_then(|value| value.to_str().ok());
+    let mut state = state();
+    if !state.observe(response.status().as_u16(), min_version, Instant::now()) {
+        return;
+    }
+    if let Some((_, reason)) = &state.paused {
+        error!(
+            "Uploads are paused because {reason}. This is version {}, please update: {RELEASES_URL}",
+            env!("CARGO_PKG_VERSION")
+        );
+    } else {
+        info!("The Deadlock API accepts this version again, resuming uploads");
+    }
+    drop(state);
+    crate::systemd::refresh_status();
+}
+
+/// Returns why uploads are paused, or `None` if they may go ahead. Once an hour this returns
+/// `None` to a single caller, to let one upload find out whether the version is still refused.
+pub(crate) fn paused() -> Option<String> {
+    state().check(Instant::now()).map(str::to_string)
+}
+
+/// Why uploads are paused, for the status shown by `systemctl status`.
+#[cfg(target_os = "linux")]
+pub(crate) fn pause_reason() -> Option<String> {
+    state().paused.as_ref().map(|(_, reason)| reason.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_observe() {
+        let mut kill_switch = KillSwitch { paused: None };
+        let now = Instant::now();
+
+        assert!(!kill_switch.observe(200, None, now));
+        assert!(!kill_switch.observe(200, Some("0.0.1"), now));
+        assert_eq!(kill_switch.check(now), None);
+
+        assert!(kill_switch.observe(200, Some("999.0.0"), now));
+        assert!(kill_switch.check(now).is_some());
+        // Unrelated errors do not resume uploads
+        assert!(!kill_switch.observe(500, None, now));
+        assert!(kill_switch.check(now).is_some());
+        // A 426 carrying the header is still a refusal
+        assert!(!kill_switch.observe(426, Some("999.0.0"), now));
+        assert!(kill_switch.check(now).is_some());
+        // One upload is let through every hour, the others stay paused
+        let later = now + RETRY_INTERVAL;
+        assert_eq!(kill_switch.check(later), None);
+        assert!(kill_switch.check(later).is_some());
+
+        assert!(kill_switch.observe(200, None, now));
+        assert_eq!(kill_switch.check(now), None);
+        assert!(kill_switch.observe(426, None, now));
+        assert!(kill_switch.check(now).is_some());
+    }
+}
@@ -12,24 +12,101 @@
 
 use std::path::PathBuf;
 
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
 use tracing::{error, info, warn};
-use tracing_subscriber::EnvFilter;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer, Registry};
+
+/// Output format for stdout and the rolling log file.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+enum LogFormat {
+    /// Human-readable text
+    #[default]
+    Text,
+    /// Newline-delimited JSON, including span fields
+    Json,
+}
 
 /// Deadlock API Ingest — uploads match data from Steam's HTTP cache.
 #[derive(Parser)]
 #[command(version)]
+#[allow(clippy::struct_excessive_bools)]
 struct Args {
     /// Disable statlocker integration
     #[arg(long)]
     no_statlocker: bool,
 
+    /// How the uploader is identified to the Deadlock API, Statlocker and the local history
+    #[arg(long, value_enum, default_value_t = privacy::Anonymity::Full)]
+    anonymity: privacy::Anonymity,
+
     /// Ingest once and exit (no file watching)
     #[arg(long)]
     once: bool,
 
+    /// Only watch the Steam cache while Deadlock is running and shortly after,
+    /// catching up on what was missed when the game starts again
+    #[arg(long)]
+    only_while_playing: bool,
+
+    /// Log output format for stdout and the log files
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// Number of daily log files to keep (0 keeps all of them)
+    #[arg(long, default_value_t = 7)]
+    log_retention_days: usize,
+
+    /// Base URL of the Deadlock API
+    #[arg(long, default_value = "https://api.deadlock-api.com")]
+    api_url: String,
+
+    /// Seconds to wait for a connection to a server
+    #[arg(long, default_value_t = http::DEFAULT_CONNECT_TIMEOUT.as_secs(), value_parser = clap::value_parser!(u64).range(1..))]
+    connect_timeout: u64,
+    /// Seconds to wait for a server to answer a request, and again for the rest of the answer
+    /// Seconds to wait for a server to answer a request
+    #[arg(long, default_value_t = http::DEFAULT_READ_TIMEOUT.as_secs(), value_parser = clap::value_parser!(u64).range(1..))]
+    read_timeout: u64,
+
+    /// Proxy for all requests, e.g. `http://proxy:3128` or `socks5://proxy:1080`.
+    /// Defaults to the `ALL_PROXY`, `HTTPS_PROXY` or `HTTP_PROXY` environment variable
+    #[arg(long)]
+    proxy: Option<String>,
+
+    /// PEM file with root certificates to trust in addition to the built-in ones,
+    /// e.g. of a corporate TLS proxy. Can be given multiple times
+    #[arg(long = "ca-cert", value_name = "PATH")]
+    ca_certs: Vec<PathBuf>,
+
+    /// Check GitHub for new releases: `notify` logs when one is available, `install` also
+    /// downloads it, verifies its signature and replaces this binary, used from the next start
+    #[arg(long, value_enum, default_value_t = update::UpdateMode::Off)]
+    update: update::UpdateMode,
+
+    /// Release endpoint used by `--update`
+    #[arg(long, default_value = update::DEFAULT_UPDATE_URL, hide = true)]
+    update_url: String,
+
+    /// Encrypt the uploader identity of salts stored in the local history. The key is stored
+    /// beside it, so this does not protect against anyone who can read the data directory
+    #[arg(long)]
+    encrypt_history: bool,
+
+    /// File holding an API key for trusted ingesters, sent with uploads.
+    /// The `DEADLOCK_API_KEY` environment variable takes precedence, the OS keyring is used otherwise
+    #[arg(long, value_name = "PATH")]
+    api_key_file: Option<PathBuf>,
+
+    /// Send a random id, stable for this install, with requests to the Deadlock API,
+    /// to help the API team debug problems with this install
+    #[arg(long)]
+    send_install_id: bool,
+
+    #[command(subcommand)]
+    subcommand: Option<Commands>,
+
     /// Game command to wrap (launch wrapper mode).
     /// When provided, the watcher runs in the background while the game
     /// runs as a child process, and exits when the game exits.
@@ -38,146 +115,506 @@ struct Args {
     command: Vec<String>,
 }
 
+#[derive(Subcommand)]
+enum Commands {
+    /// Show ingested salts from the local history
+    History {
+        /// Only show salts for this match
+        #[arg(long)]
+        match_id: Option<u64>,
+
+        /// Number of salts to show, from the most recently played matches
+        #[arg(long, default_value_t = 20)]
+        limit: usize,
+    },
+    /// Export the local history as newline-delimited JSON
+    Export {
+        /// File to write to (defaults to stdout)
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Save new salts from the Steam cache for a later upload, without uploading anything.
+    /// Meant to be run regularly (e.g. by a scheduled task) when the uploader is not kept running,
+    /// so salts are not lost when Steam evicts them from its cache.
+    Snapshot,
+    /// Manage the Windows service
+    Service {
+        #[command(subcommand)]
+        command: service::ServiceCommand,
+    },
+    /// Manage the API key stored in the OS keyring
+    ApiKey {
+        #[command(subcommand)]
+        command: api_key::ApiKeyCommand,
+    },
+}
+
+mod api_key;
 mod error;
+mod game_session;
+mod history;
+mod http;
 mod ingestion_cache;
+mod instance_lock;
+mod ipc;
+mod kill_switch;
+mod match_state;
+mod outbox;
+mod privacy;
 mod scan_cache;
+mod service;
+mod shutdown;
+mod snapshot;
 mod statlocker;
+mod steam_library;
 mod steam_user;
+mod systemd;
+#[cfg(test)]
+mod test_utils;
+mod update;
 mod utils;
+mod validation;
+mod vdf;
 
 /// Returns the directory for log files.
 /// - Linux: `~/.local/share/deadlock-api-ingest/logs/`
 /// - macOS: `~/Library/Application Support/deadlock-api-ingest/logs/`
 /// - Windows: `C:\Users\<User>\AppData\Roaming\deadlock-api-ingest\logs\`
 fn get_log_dir() -> Option<PathBuf> {
-    let log_dir = dirs::data_dir()?.join("deadlock-api-ingest").join("logs");
+    let log_dir = utils::data_dir()?.join("logs");
     std::fs::create_dir_all(&log_dir).ok()?;
     Some(log_dir)
 }
 
-fn init_tracing() {
+type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+fn fmt_layer<W>(format: LogFormat, writer: W, ansi: bool) -> BoxedLayer
+where
+    W: for<'w> tracing_subscriber::fmt::MakeWriter<'w> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_ansi(ansi);
+    match format {
+        LogFormat::Text => layer.boxed(),
+        LogFormat::Json => layer
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .boxed(),
+    }
+}
+
+/// Console logs go to stderr when `to_stderr` is set, so subcommand output on stdout stays clean.
+/// With `event_log`, info and above also go to the Windows Event Log.
+fn init_tracing(format: LogFormat, retention_days: usize, to_stderr: bool, event_log: bool) {
     let env_filter = EnvFilter::try_from_default_env()
         .unwrap_or(EnvFilter::new("debug,reqwest=warn,rustls=warn"));
-    let stdout_layer = tracing_subscriber::fmt::layer();
+    let ansi = format == LogFormat::Text;
+    let mut layers = vec![if to_stderr {
+        fmt_layer(format, std::io::stderr, ansi)
+    } else if let Some(layer) = systemd::journald_layer() {
+        layer
+    } else {
+        fmt_layer(format, std::io::stdout, ansi)
+    }];
 
-    let file_layer = get_log_dir().and_then(|log_dir| {
-        tracing_appender::rolling::RollingFileAppender::builder()
+    if let Some(log_dir) = get_log_dir() {
+        let mut builder = tracing_appender::rolling::RollingFileAppender::builder()
             .rotation(tracing_appender::rolling::Rotation::DAILY)
             .filename_prefix("deadlock-api-ingest")
-            .filename_suffix("log")
-            .max_log_files(7)
-            .build(&log_dir)
-            .ok()
-            .map(|appender| {
-                tracing_subscriber::fmt::layer()
-                    .with_writer(appender)
-                    .with_ansi(false)
-            })
-    });
+            .filename_suffix(match format {
+                LogFormat::Text => "log",
+                LogFormat::Json => "jsonl",
+            });
+        if retention_days > 0 {
+            builder = builder.max_log_files(retention_days);
+        }
+        if let Ok(appender) = builder.build(&log_dir) {
+            layers.push(fmt_layer(format, appender, false));
+        }
+    }
+    if event_log && let Some(layer) = service::event_log_layer() {
+        layers.push(layer);
+    }
 
     tracing_subscriber::registry()
-        .with(stdout_layer)
-        .with(file_layer)
+        .with(layers)
         .with(env_filter)
         .init();
 }
 
-fn run_launch_wrapper<F: FnOnce() + Send + 'static>(background_work: F, command: &[String]) -> i32 {
-    std::thread::spawn(background_work);
+/// How long the background ingestion gets to wind down once the game has exited.
+const WRAPPER_SHUTDOWN_TIMEOUT: core::time::Duration = core::time::Duration::from_secs(2);
+
+/// How often `--only-while-playing` checks whether the game was started
+const SESSION_POLL_INTERVAL: core::time::Duration = core::time::Duration::from_secs(15);
+
+/// How long pending Statlocker notifications get to be sent before exiting.
+const STATLOCKER_FLUSH_TIMEOUT: core::time::Duration = core::time::Duration::from_secs(5);
+
+/// How long the post-game scan and its uploads may take before the wrapper exits anyway.
+const FINAL_SCAN_TIMEOUT: core::time::Duration = core::time::Duration::from_secs(10);
+
+/// Runs `command` while `background_work` runs on another thread. Once the game has exited,
+/// the background work is stopped and `final_work` gets the launch time and a bounded amount
+/// of time to pick up anything written while the game was closing.
+fn run_launch_wrapper<F, G>(background_work: F, final_work: G, command: &[String]) -> i32
+where
+    F: FnOnce() + Send + 'static,
+    G: FnOnce(std::time::SystemTime) + Send + 'static,
+{
+    let launched_at = std::time::SystemTime::now();
+    let background = std::thread::spawn(background_work);
     info!("Launching game: {}", command.join(" "));
-    match std::process::Command::new(&command[0])
-        .args(&command[1..])
-        .status()
-    {
-        Ok(s) => {
-            info!("Game exited with status: {s}");
-            s.code().unwrap_or(0)
+    // The environment (Steam's and Proton's variables) is inherited as is
+    let mut game = std::process::Command::new(&command[0]);
+    game.args(&command[1..]);
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(&mut game, 0);
+    let exit_code = match game.spawn() {
+        Ok(mut child) => {
+            #[cfg(unix)]
+            shutdown::set_child_group(Some(child.id()));
+            #[cfg(unix)]
+            let guard = shutdown::spawn_orphan_guard(child.id())
+                .inspect_err(|e| warn!("Failed to start the guard that stops the game if this process is killed: {e}"))
+                .ok();
+            let status = child.wait();
+            #[cfg(unix)]
+            {
+                shutdown::set_child_group(None);
+                if let Some(mut guard) = guard {
+                    let _ = guard.kill();
+                    let _ = guard.wait();
+                }
+            }
+            match status {
+                Ok(s) => {
+                    info!("Game exited with status: {s}");
+                    exit_code(s)
+                }
+                Err(e) => {
+                    error!("Failed to wait for game command '{}': {e}", command[0]);
+                    1
+                }
+            }
         }
         Err(e) => {
             error!("Failed to launch game command '{}': {e}", command[0]);
             1
         }
+    };
+    shutdown::request();
+    if !shutdown::join_with_timeout(background, WRAPPER_SHUTDOWN_TIMEOUT) {
+        warn!(
+            "Background ingestion did not stop in time, pending salts will be retried on the next start"
+        );
+    }
+    let final_scan = std::thread::spawn(move || final_work(launched_at));
+    if !shutdown::join_with_timeout(final_scan, FINAL_SCAN_TIMEOUT) {
+        warn!("Final scan did not finish in time, pending salts will be retried on the next start");
     }
+    exit_code
 }
 
-fn main() {
-    init_tracing();
+/// Launches the game while another instance watches the cache, telling it when the game
+/// starts and stops so it rescans right after the game exits. If it cannot be reached and has
+/// exited, the final scan runs here instead.
+fn run_handoff_wrapper(cache_dir: &std::path::Path, args: &Args) -> i32 {
+    match ipc::notify(cache_dir, ipc::Event::GameStarted) {
+        Ok(()) => info!("Another instance is already watching the Steam cache, handing off to it"),
+        Err(e) => warn!(
+            "Another instance is already watching the Steam cache, but it could not be reached: {e}"
+        ),
+    }
+    let cache_dir = cache_dir.to_path_buf();
+    let encrypt_history = args.encrypt_history;
+    run_launch_wrapper(
+        || {},
+        move |launched_at| {
+            let event = ipc::Event::GameStopped {
+                launched_at: ipc::unix_seconds(launched_at),
+            };
+            let Err(e) = ipc::notify(&cache_dir, event) else {
+                return;
+            };
+            // If the other instance is gone, take over its history and outbox for the scan
+            if let Some(_lock) = instance_lock::try_acquire(&cache_dir) {
+                warn!("Failed to report the game exit to the other instance, scanning here: {e}");
+                open_stores(encrypt_history);
+                scan_cache::recent_cache_dir_ingest(&cache_dir, launched_at);
+                return;
+            }
+            // It still holds the lock, so it is alive and its watcher picks up the new files.
+            // Scanning here would upload them twice, without its history and outbox.
+            if let Err(e) = ipc::notify(&cache_dir, event) {
+                warn!(
+                    "Failed to report the game exit to the other instance, \
+                     leaving the new files to its watcher: {e}"
+                );
+            }
+        },
+        &args.command,
+    )
+}
+
+/// Opens the history and the outbox. Only the instance holding the instance lock may do so, as
+/// each of them can only be opened by one process at a time.
+fn open_stores(encrypt_history: bool) {
+    history::init(encrypt_history);
+    outbox::init();
+}
+
+/// Locates Steam and its cache directory, for the commands that do not watch it.
+fn locate_cache_dir() -> Result<std::path::PathBuf, String> {
+    let steam_dir = steamlocate::SteamDir::locate()
+        .map_err(|e| format!("Could not find Steam directory: {e}"))?;
+    find_cache_dir(steam_dir.path())
+        .ok_or_else(|| "Could not find Steam cache directory".to_string())
+}
+
+/// Returns Steam's `appcache/httpcache` directory, searching the home directory
+/// if it is not in the Steam installation.
+fn find_cache_dir(steam_path: &std::path::Path) -> Option<std::path::PathBuf> {
+    let cache_dir = steam_path.join("appcache").join("httpcache");
+    if cache_dir.exists() {
+        return Some(cache_dir);
+    }
+    warn!(
+        "Steam cache directory not found at {}, searching the home directory",
+        cache_dir.display()
+    );
+
+    let home_dir = dirs::home_dir().unwrap_or_default();
+    let appcache_search = std::ffi::OsStr::new("appcache");
+    walkdir::WalkDir::new(&home_dir)
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter(|e| e.file_type().is_dir())
+        .find(|entry| {
+            entry.file_name() == "httpcache"
+                && entry
+                    .path()
+                    .parent()
+                    .and_then(|p| p.file_name())
+                    .is_some_and(|p| p == appcache_search)
+        })
+        .map(walkdir::DirEntry::into_path)
+}
 
+/// Maps the game's exit status to the wrapper's exit code, using the shell convention
+/// of 128 + signal number when the game was killed by a signal.
+fn exit_code(status: std::process::ExitStatus) -> i32 {
+    #[cfg(unix)]
+    if let Some(signal) = std::os::unix::process::ExitStatusExt::signal(&status) {
+        return 128 + signal;
+    }
+    status.code().unwrap_or(1)
+}
+
+/// Runs the cache watcher until shutdown is requested, restarting it after errors.
+fn watch_until_shutdown(cache_dir: &std::path::Path) {
+    scan_cache::supervise_watcher(cache_dir, None, || true);
+}
+
+/// Like [`watch_until_shutdown`], but only watches while a play session is in progress.
+/// Each time the watcher resumes, the files modified while it was paused are scanned first.
+fn watch_during_sessions(cache_dir: &std::path::Path) {
+    let mut session = game_session::SessionMonitor::new();
+    let mut paused_since = std::time::SystemTime::now();
+    info!("Watching the Steam cache only while Deadlock is running");
+    while !shutdown::is_requested() {
+        systemd::watchdog();
+        if !session.is_active() {
+            systemd::set_state("Waiting for Deadlock to start");
+            if shutdown::wait_timeout(SESSION_POLL_INTERVAL) {
+                break;
+            }
+            continue;
+        }
+        systemd::set_state("Watching the Steam cache");
+        scan_cache::supervise_watcher(cache_dir, Some(paused_since), || session.is_active());
+        paused_since = std::time::SystemTime::now();
+    }
+}
+
+fn main() {
     let args = Args::parse();
+    let as_service = matches!(
+        args.subcommand,
+        Some(Commands::Service {
+            command: service::ServiceCommand::Run
+        })
+    );
+
+    init_tracing(
+        args.log_format,
+        args.log_retention_days,
+        args.subcommand.is_some() && !as_service,
+        as_service,
+    );
 
     if let Some(log_dir) = get_log_dir() {
         info!("Log files are being written to: {}", log_dir.display());
     }
+    shutdown::install_handler();
 
     if args.no_statlocker {
         statlocker::disable();
     }
 
+    utils::set_api_url(&args.api_url);
+    http::set_install_mode(install_mode(&args, as_service));
+    let network = http::Settings {
+        connect_timeout: core::time::Duration::from_secs(args.connect_timeout),
+        read_timeout: core::time::Duration::from_secs(args.read_timeout),
+        proxy: args.proxy.clone(),
+        ca_certs: args.ca_certs.clone(),
+    };
+    if let Err(e) = http::configure(&network) {
+        error!("{e}");
+        std::process::exit(1);
+    }
+    if args.send_install_id {
+        http::enable_install_id();
+    }
+    privacy::set_anonymity(args.anonymity);
+
+    if let Some(subcommand) = &args.subcommand {
+        let result = match subcommand {
+            Commands::History { match_id, limit } => {
+                history::print_history(*match_id, *limit, args.encrypt_history)
+            }
+            Commands::Export { output } => history::export(output.as_deref(), args.encrypt_history),
+            Commands::Snapshot => snapshot::run(args.encrypt_history),
+            Commands::Service {
+                command: service::ServiceCommand::Run,
+            } => service::dispatch(|| {
+                let mut args = Args::parse();
+                // Services installed before it was rejected below may still pass it
+                if args.only_while_playing {
+                    warn!(
+                        "--only-while-playing is not supported by the service, as it cannot see \
+                         the game of the signed-in user, watching the cache all the time"
+                    );
+                    args.only_while_playing = false;
+                }
+                run(&args)
+            }),
+            Commands::Service {
+                command: service::ServiceCommand::Install,
+            } if args.only_while_playing => Err(
+                "--only-while-playing is not supported by the service, as it cannot see the game \
+                 of the signed-in user"
+                    .to_string(),
+            ),
+            Commands::Service { command } => service::manage(*command),
+            Commands::ApiKey { command } => api_key::manage(*command),
+        };
+        if let Err(e) = result {
+            error!("{e}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    std::process::exit(run(&args));
+}
+
+/// How this process was started, reported to the Deadlock API in the `User-Agent`.
+fn install_mode(args: &Args, as_service: bool) -> http::InstallMode {
+    if as_service {
+        http::InstallMode::WindowsService
+    } else if matches!(args.subcommand, Some(Commands::Snapshot)) {
+        http::InstallMode::Snapshot
+    } else if !args.command.is_empty() {
+        http::InstallMode::LaunchOption
+    } else {
+        http::InstallMode::detect()
+    }
+}
+
+/// Watches the Steam cache and uploads salts until shutdown, or runs the game in
+/// launch wrapper mode. Returns the exit code.
+fn run(args: &Args) -> i32 {
+    info!("Uploader identity: {}", privacy::describe());
+    if let Err(e) = api_key::init(args.api_key_file.as_deref()) {
+        // A broken key file must not keep the game from starting
+        if args.command.is_empty() {
+            error!("{e}");
+            return 1;
+        }
+        warn!("{e}, uploading without an API key");
+    }
+    update::start(args.update, &args.update_url);
+    if let Some(source) = steam_user::current_user_source() {
+        info!("Active Steam account resolved from {source}");
+    } else {
+        warn!("Could not resolve the active Steam account");
+    }
+
     let Ok(steam_dir) = steamlocate::SteamDir::locate() else {
         error!("Could not find Steam directory. Waiting 30s before exiting.");
         std::thread::sleep(core::time::Duration::from_secs(30));
-        return;
+        return 0;
     };
     let steam_path = steam_dir.path();
-    let mut cache_dir = steam_path.join("appcache").join("httpcache");
-
-    if !cache_dir.exists() {
-        let home_dir = dirs::home_dir().unwrap_or_default();
-        let appcache_search = std::ffi::OsStr::new("appcache");
-        for entry in walkdir::WalkDir::new(&home_dir)
-            .into_iter()
-            .filter_map(Result::ok)
-            .filter(|e| e.file_type().is_dir())
-        {
-            if entry.file_name() == "httpcache"
-                && entry
-                    .path()
-                    .parent()
-                    .and_then(|p| p.file_name())
-                    .is_some_and(|p| p == appcache_search)
-            {
-                cache_dir = entry.path().to_path_buf();
-                break;
-            }
-        }
-        if !cache_dir.exists() {
-            error!(
-                "Could not find Steam cache directory at {}. Waiting 30s before exiting.",
-                cache_dir.display()
-            );
-            std::thread::sleep(core::time::Duration::from_secs(30));
-            return;
-        }
+    steam_user::watch_login_users(steam_path);
+    if let Some(library) = steam_library::deadlock_library(steam_path) {
+        info!("Deadlock is installed in library {}", library.display());
+    }
+    let Some(cache_dir) = find_cache_dir(steam_path) else {
+        error!("Could not find Steam cache directory. Waiting 30s before exiting.");
+        std::thread::sleep(core::time::Duration::from_secs(30));
+        return 0;
+    };
+
+    // Held until the process exits
+    let _lock = if let Some(lock) = instance_lock::try_acquire(&cache_dir) {
+        lock
+    } else if !args.command.is_empty() {
+        return run_handoff_wrapper(&cache_dir, args);
+    } else if args.once {
+        info!("Another instance is already watching the Steam cache, nothing to do");
+        return 0;
+    } else {
+        let Some(lock) = instance_lock::wait_for(&cache_dir) else {
+            return 0;
+        };
+        lock
+    };
+    open_stores(args.encrypt_history);
+    statlocker::resume_pending();
+    if !args.once {
+        ipc::serve(&cache_dir);
     }
 
     if !args.command.is_empty() {
+        let watch_dir = cache_dir.clone();
         let exit_code = run_launch_wrapper(
             move || {
-                scan_cache::initial_cache_dir_ingest(&cache_dir);
-                loop {
-                    if let Err(e) = scan_cache::watch_cache_dir(&cache_dir) {
-                        warn!("Error in cache watcher: {e:?}");
-                    }
-                    std::thread::sleep(core::time::Duration::from_secs(10));
-                }
+                scan_cache::initial_cache_dir_ingest(&watch_dir);
+                watch_until_shutdown(&watch_dir);
             },
+            move |launched_at| scan_cache::recent_cache_dir_ingest(&cache_dir, launched_at),
             &args.command,
         );
-        std::process::exit(exit_code);
+        statlocker::flush(STATLOCKER_FLUSH_TIMEOUT);
+        return exit_code;
     }
 
     scan_cache::initial_cache_dir_ingest(&cache_dir);
-
-    if args.once {
-        std::process::exit(0);
-    }
-
-    loop {
-        if let Err(e) = scan_cache::watch_cache_dir(&cache_dir) {
-            warn!("Error in cache watcher: {e:?}");
-        }
-        std::thread::sleep(core::time::Duration::from_secs(10));
+    systemd::ready("Watching the Steam cache");
+    if args.only_while_playing && !args.once {
+        watch_during_sessions(&cache_dir);
+    } else if !args.once {
+        watch_until_shutdown(&cache_dir);
     }
+    systemd::stopping();
+    statlocker::flush(STATLOCKER_FLUSH_TIMEOUT);
+    info!("Shut down cleanly");
+    0
 }
 
 #[cfg(test)]
@@ -187,6 +624,8 @@ mod tests {
 
     #[test]
     fn test_launch_wrapper_starts_command_promptly() {
+        // The wrapper requests a shutdown and registers the game's process group, both global
+        let _guard = shutdown::test_guard();
         let marker =
             std::env::temp_dir().join(format!("deadlock-launch-test-{}", std::process::id()));
         let _ = std::fs::remove_file(&marker);
@@ -196,6 +635,7 @@ mod tests {
         let start = Instant::now();
         let exit_code = run_launch_wrapper(
             || std::thread::sleep(core::time::Duration::from_secs(30)),
+            |_| {},
             &command,
         );
         let elapsed = start.elapsed();
@@ -214,4 +654,32 @@ mod tests {
 
         let _ = std::fs::remove_file(&marker);
     }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_launch_wrapper_propagates_signal_exit() {
+        let _guard = shutdown::test_guard();
+        let command = ["sh", "-c", "kill -9 $$"].map(String::from);
+        assert_eq!(run_launch_wrapper(|| {}, |_| {}, &command), 128 + 9);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_launch_wrapper_forwards_signals() {
+        let _guard = shutdown::test_guard();
+        let command = ["sleep", "30"].map(String::from);
+        let wrapper = std::thread::spawn(move || run_launch_wrapper(|| {}, |_| {}, &command));
+
+        // Keep forwarding until the game is registered and has exited
+        let start = Instant::now();
+        while !wrapper.is_finished() {
+            assert!(
+                start.elapsed() < core::time::Duration::from_secs(5),
+                "game did not exit after SIGTERM was forwarded"
+            );
+            shutdown::signal_child(false);
+            std::thread::sleep(core::time::Duration::from_millis(100));
+        }
+        assert_eq!(wrapper.join().unwrap(), 128 + 15);
+    }
 }
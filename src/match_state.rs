@@ -0,0 +1,273 @@
+use crate::http;
+use crate::utils::{Salts, api_url};
+use core::sync::atomic::{AtomicBool, Ordering};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashSet};
+use std::sync::{Mutex, OnceLock};
+use tracing::{debug, warn};
+
+/// Maximum number of matches tracked. Match ids grow over time, so the lowest ones are dropped first.
+const MAX_TRACKED_MATCHES: usize = 10_000;
+
+/// Maximum number of match ids sent in a single status query
+const MAX_QUERY_BATCH: usize = 500;
+
+/// Set once the API reports that the status endpoint is unavailable, to avoid querying it again.
+static STATUS_ENDPOINT_UNAVAILABLE: AtomicBool = AtomicBool::new(false);
+
+static MATCH_STATES: OnceLock<Mutex<BTreeMap<u64, MatchState>>> = OnceLock::new();
+
+/// What is known about the salts of a single match, locally and on the server.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct MatchState {
+    pub(crate) seen_metadata: bool,
+    pub(crate) seen_replay: bool,
+    /// `None` until the server was asked about this match.
+    pub(crate) server: Option<ServerState>,
+}
+
+/// Which salts the server already has for a match.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub(crate) struct ServerState {
+    pub(crate) has_metadata: bool,
+    pub(crate) has_replay: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct ServerSaltState {
+    match_id: u64,
+    #[serde(flatten)]
+    state: ServerState,
+}
+
+impl MatchState {
+    fn observe(&mut self, salt: &Salts) {
+        self.seen_metadata |= salt.metadata_salt.is_some();
+        self.seen_replay |= salt.replay_salt.is_some();
+    }
+
+    /// Returns true unless the server is known to already have this salt.
+    fn server_needs(self, salt: &Salts) -> bool {
+        self.server.is_none_or(|server| {
+            (salt.metadata_salt.is_some() && !server.has_metadata)
+                || (salt.replay_salt.is_some() && !server.has_replay)
+        })
+    }
+
+    /// Lower is more urgent: matches the server has nothing for come first,
+    /// and within each group matches we have both halves of locally.
+    fn priority(self) -> (u8, bool) {
+        let server_rank = match self.server {
+            Some(ServerState {
+                has_metadata: false,
+                has_replay: false,
+            }) => 0,
+            None => 1,
+            Some(_) => 2,
+        };
+        (server_rank, !(self.seen_metadata && self.seen_replay))
+    }
+}
+
+fn with_states<T>(f: impl FnOnce(&mut BTreeMap<u64, MatchState>) -> T) -> T {
+    let states = MATCH_STATES.get_or_init(Default::default);
+    let mut states = states.lock().unwrap_or_else(|poisoned| {
+        warn!("Failed to lock match states");
+        poisoned.into_inner()
+    });
+    let result = f(&mut states);
+    while states.len() > MAX_TRACKED_MATCHES {
+        states.pop_first();
+    }
+    result
+}
+
+/// Asks the API which salts it already has for the given matches.
+fn fetch_server_states(base_url: &str, match_ids: &[u64]) -> Option<Vec<ServerSaltState>> {
+    let response = http::client()
+        .post(format!("{base_url}/v1/matches/salts/status"))
+        .send_json(match_ids);
+    match response {
+        Ok(mut resp) => match resp.body_mut().read_json::<Vec<ServerSaltState>>() {
+            Ok(states) => Some(states),
+            Err(e) => {
+                warn!("Failed to parse salt status response: {e}");
+                None
+            }
+        },
+        Err(ureq::Error::StatusCode(404 | 405 | 501)) => {
+            debug!("Salt status endpoint is not available, uploading all salts");
+            STATUS_ENDPOINT_UNAVAILABLE.store(true, Ordering::Relaxed);
+            None
+        }
+        Err(e) => {
+            warn!("Failed to query salt status: {e}");
+            None
+        }
+    }
+}
+
+/// Fills in the server state of every tracked match in `match_ids` that was not queried yet,
+/// if the API advertises the status endpoint.
+fn refresh_server_states(match_ids: &[u64]) {
+    if STATUS_ENDPOINT_UNAVAILABLE.load(Ordering::Relaxed)
+        || !crate::validation::supports_salt_status()
+    {
+        return;
+    }
+    let unknown: Vec<u64> = with_states(|states| {
+        match_ids
+            .iter()
+            .copied()
+            .filter(|id| states.get(id).is_some_and(|s| s.server.is_none()))
+            .collect()
+    });
+
+    for batch in unknown.chunks(MAX_QUERY_BATCH) {
+        if STATUS_ENDPOINT_UNAVAILABLE.load(Ordering::Relaxed) {
+            return;
+        }
+        let Some(server_states) = fetch_server_states(api_url(), batch) else {
+            return;
+        };
+        with_states(|states| {
+            // Matches the server did not mention are matches it has nothing for
+            for id in batch {
+                if let Some(state) = states.get_mut(id) {
+                    state.server = Some(ServerState::default());
+                }
+            }
+            for server_state in server_states {
+                if let Some(state) = states.get_mut(&server_state.match_id) {
+                    state.server = Some(server_state.state);
+                }
+            }
+        });
+    }
+}
+
+/// Records the given locally seen salts and returns the ones the server still needs, most urgent
+/// first: matches the server has no salts for at all, then matches seen in full locally.
+/// Asks the API about matches it was not asked about yet, so this is meant for batches.
+pub(crate) fn plan_uploads(salts: &[Salts]) -> Vec<Salts> {
+    let match_ids: Vec<u64> = with_states(|states| {
+        for salt in salts {
+            states.entry(salt.match_id).or_default().observe(salt);
+        }
+        let ids: HashSet<u64> = salts.iter().map(|s| s.match_id).collect();
+        ids.into_iter().collect()
+    });
+    refresh_server_states(&match_ids);
+
+    with_states(|states| {
+        let mut planned: Vec<((u8, bool), Salts)> = salts
+            .iter()
+            .filter_map(|salt| {
+                let state = states.get(&salt.match_id).copied().unwrap_or_default();
+                if state.server_needs(salt) {
+                    Some((state.priority(), *salt))
+                } else {
+                    debug!(
+                        "Skipping salts for match {}, the server already has them",
+                        salt.match_id
+                    );
+                    None
+                }
+            })
+            .collect();
+        planned.sort_by_key(|(priority, _)| *priority);
+        planned.into_iter().map(|(_, salt)| salt).collect()
+    })
+}
+
+/// Records a single locally seen salt and returns true unless the server is already known to have
+/// it. Unlike [`plan_uploads`], this never waits for the API, as it runs for every cache write.
+pub(crate) fn needs_upload(salt: &Salts) -> bool {
+    with_states(|states| {
+        let state = states.entry(salt.match_id).or_default();
+        state.observe(salt);
+        state.server_needs(salt)
+    })
+}
+
+/// Records that the server now has the given salt.
+pub(crate) fn mark_uploaded(salt: &Salts) {
+    with_states(|states| {
+        let state = states.entry(salt.match_id).or_default();
+        state.observe(salt);
+        let server = state.server.get_or_insert_default();
+        server.has_metadata |= salt.metadata_salt.is_some();
+        server.has_replay |= salt.replay_salt.is_some();
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{MockResponse, MockServer};
+
+    fn salt(match_id: u64, metadata: bool) -> Salts {
+        Salts {
+            match_id,
+            cluster_id: 1,
+            metadata_salt: metadata.then_some(1),
+            replay_salt: (!metadata).then_some(1),
+            username: None,
+        }
+    }
+
+    #[test]
+    fn test_server_needs_and_priority() {
+        let unknown = MatchState::default();
+        assert!(unknown.server_needs(&salt(1, true)));
+        assert_eq!(unknown.priority(), (1, true));
+
+        let partial = MatchState {
+            server: Some(ServerState {
+                has_metadata: true,
+                has_replay: false,
+            }),
+            ..MatchState::default()
+        };
+        assert!(!partial.server_needs(&salt(1, true)));
+        assert!(partial.server_needs(&salt(1, false)));
+        assert_eq!(partial.priority(), (2, true));
+
+        let missing = MatchState {
+            seen_metadata: true,
+            seen_replay: true,
+            server: Some(ServerState::default()),
+        };
+        assert_eq!(missing.priority(), (0, false));
+    }
+
+    #[test]
+    fn test_needs_upload() {
+        let uploaded = salt(u64::MAX - 1, true);
+        assert!(needs_upload(&uploaded));
+        mark_uploaded(&uploaded);
+        assert!(!needs_upload(&uploaded));
+        assert!(needs_upload(&salt(u64::MAX - 1, false)));
+    }
+
+    #[test]
+    fn test_fetch_server_states() {
+        let server = MockServer::start(vec![MockResponse::json(
+            200,
+            r#"[{"match_id":1,"has_metadata":true,"has_replay":false}]"#,
+        )]);
+        let states = fetch_server_states(&server.url, &[1, 2]).unwrap();
+        assert_eq!(states.len(), 1);
+        assert_eq!(states[0].match_id, 1);
+        assert!(states[0].state.has_metadata);
+        assert!(!states[0].state.has_replay);
+
+        let requests = server.requests();
+        assert_eq!(
+            requests[0].request_line,
+            "POST /v1/matches/salts/status HTTP/1.1"
+        );
+        let body: Vec<u64> = serde_json::from_str(&requests[0].body).unwrap();
+        assert_eq!(body, [1, 2]);
+    }
+}
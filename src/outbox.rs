@@ -0,0 +1,252 @@
+use crate::utils::Salts;
+use redb::{Database, ReadableDatabase, ReadableTable, TableDefinition};
+use std::path::Path;
+use std::sync::OnceLock;
+use tracing::warn;
+
+/// Name of the outbox database file
+const OUTBOX_FILE_NAME: &str = "outbox.redb";
+
+/// Salts waiting to be uploaded, keyed by `(match_id, kind)` like the history.
+const SALTS_TABLE: TableDefinition<(u64, u8), &[u8]> = TableDefinition::new("salts");
+
+/// Match ids waiting to be sent to Statlocker.
+const STATLOCKER_TABLE: TableDefinition<u64, ()> = TableDefinition::new("statlocker");
+
+/// Small pieces of state, e.g. when the cache was last snapshotted.
+const STATE_TABLE: TableDefinition<&str, u64> = TableDefinition::new("state");
+
+/// Key in [`STATE_TABLE`] for the time of the last snapshot, in seconds since the Unix epoch
+const SNAPSHOT_AT_KEY: &str = "snapshot_at";
+
+static OUTBOX: OnceLock<Outbox> = OnceLock::new();
+
+/// Write-ahead store for work that has not been delivered yet.
+///
+/// Salts and Statlocker notifications are written here before they are sent and removed once
+/// delivered, so anything in flight when the process exits (or crashes) is retried on the next start.
+pub(crate) struct Outbox {
+    db: Database,
+}
+
+fn salt_key(salt: &Salts) -> (u64, u8) {
+    (salt.match_id, u8::from(salt.metadata_salt.is_none()))
+}
+
+impl Outbox {
+    pub(crate) fn open(path: &Path) -> Result<Self, redb::Error> {
+        let db = Database::create(path)?;
+        let txn = db.begin_write()?;
+        txn.open_table(SALTS_TABLE)?;
+        txn.open_table(STATLOCKER_TABLE)?;
+        txn.open_table(STATE_TABLE)?;
+        txn.commit()?;
+        Ok(Self { db })
+    }
+
+    pub(crate) fn push_salts(&self, salts: &[Salts]) -> Result<(), redb::Error> {
+        let txn = self.db.begin_write()?;
+        {
+            let mut table = txn.open_table(SALTS_TABLE)?;
+            for salt in salts {
+                // The identity is recomputed when draining, so it follows later changes to
+                // the anonymity setting or the salt instead of being kept on disk
+                let salt = Salts {
+                    username: None,
+                    ..*salt
+                };
+                let value =
+                    serde_json::to_vec(&salt).map_err(|e| redb::Error::Corrupted(e.to_string()))?;
+                table.insert(salt_key(&salt), value.as_slice())?;
+            }
+        }
+        txn.commit()?;
+        Ok(())
+    }
+
+    pub(crate) fn remove_salts(&self, salts: &[Salts]) -> Result<(), redb::Error> {
+        let txn = self.db.begin_write()?;
+        {
+            let mut table = txn.open_table(SALTS_TABLE)?;
+            for salt in salts {
+                table.remove(salt_key(salt))?;
+            }
+        }
+        txn.commit()?;
+        Ok(())
+    }
+
+    pub(crate) fn pending_salts(&self) -> Result<Vec<Salts>, redb::Error> {
+        let txn = self.db.begin_read()?;
+        let mut salts = Vec::new();
+        for entry in txn.open_table(SALTS_TABLE)?.iter()? {
+            let (_, value) = entry?;
+            match serde_json::from_slice::<Salts>(value.value()) {
+                Ok(salt) => salts.push(Salts {
+                    username: crate::privacy::uploader_identity(),
+                    ..salt
+                }),
+                Err(e) => warn!("Dropping unreadable outbox entry: {e}"),
+            }
+        }
+        Ok(salts)
+    }
+
+    pub(crate) fn push_statlocker(&self, match_id: u64) -> Result<(), redb::Error> {
+        let txn = self.db.begin_write()?;
+        txn.open_table(STATLOCKER_TABLE)?.insert(match_id, ())?;
+        txn.commit()?;
+        Ok(())
+    }
+
+    pub(crate) fn remove_statlocker(&self, match_id: u64) -> Result<(), redb::Error> {
+        let txn = self.db.begin_write()?;
+        txn.open_table(STATLOCKER_TABLE)?.remove(match_id)?;
+        txn.commit()?;
+        Ok(())
+    }
+
+    pub(crate) fn pending_statlocker(&self) -> Result<Vec<u64>, redb::Error> {
+        let txn = self.db.begin_read()?;
+        let mut match_ids = Vec::new();
+        for entry in txn.open_table(STATLOCKER_TABLE)?.iter()? {
+            match_ids.push(entry?.0.value());
+        }
+        Ok(match_ids)
+    }
+
+    pub(crate) fn snapshot_at(&self) -> Result<Option<u64>, redb::Error> {
+        let txn = self.db.begin_read()?;
+        let value = txn.open_table(STATE_TABLE)?.get(SNAPSHOT_AT_KEY)?;
+        Ok(value.map(|value| value.value()))
+    }
+
+    pub(crate) fn set_snapshot_at(&self, seconds: u64) -> Result<(), redb::Error> {
+        let txn = self.db.begin_write()?;
+        txn.open_table(STATE_TABLE)?
+            .insert(SNAPSHOT_AT_KEY, seconds)?;
+        txn.commit()?;
+        Ok(())
+    }
+}
+
+/// Opens the outbox in the data directory. Without it, undelivered work is simply lost on exit.
+pub(crate) fn init() {
+    let Some(data_dir) = crate::utils::data_dir() else {
+        warn!("Failed to determine data directory, outbox is disabled");
+        return;
+    };
+    match Outbox::open(&data_dir.join(OUTBOX_FILE_NAME)) {
+        Ok(outbox) => {
+            let _ = OUTBOX.set(outbox);
+        }
+        Err(e) => warn!("Failed to open outbox: {e}"),
+    }
+}
+
+fn with_outbox<T: Default>(f: impl FnOnce(&Outbox) -> Result<T, redb::Error>) -> T {
+    let Some(outbox) = OUTBOX.get() else {
+        return T::default();
+    };
+    f(outbox).unwrap_or_else(|e| {
+        warn!("Outbox operation failed: {e}");
+        T::default()
+    })
+}
+
+pub(crate) fn push_salts(salts: &[Salts]) {
+    with_outbox(|o| o.push_salts(salts));
+}
+
+pub(crate) fn remove_salts(salts: &[Salts]) {
+    with_outbox(|o| o.remove_salts(salts));
+}
+
+pub(crate) fn pending_salts() -> Vec<Salts> {
+    with_outbox(Outbox::pending_salts)
+}
+
+pub(crate) fn push_statlocker(match_id: u64) {
+    with_outbox(|o| o.push_statlocker(match_id));
+}
+
+pub(crate) fn remove_statlocker(match_id: u64) {
+    with_outbox(|o| o.remove_statlocker(match_id));
+}
+
+pub(crate) fn pending_statlocker() -> Vec<u64> {
+    with_outbox(Outbox::pending_statlocker)
+}
+
+/// Whether the outbox could be opened, e.g. not while another instance has it open.
+pub(crate) fn is_available() -> bool {
+    OUTBOX.get().is_some()
+}
+
+/// Time of the last snapshot, in seconds since the Unix epoch.
+pub(crate) fn snapshot_at() -> Option<u64> {
+    with_outbox(Outbox::snapshot_at)
+}
+
+pub(crate) fn set_snapshot_at(seconds: u64) {
+    with_outbox(|o| o.set_snapshot_at(seconds));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::privacy::Identity;
+
+    #[test]
+    fn test_salts_roundtrip() {
+        let dir = std::env::temp_dir().join(format!("deadlock-outbox-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let outbox = Outbox::open(&dir.join(OUTBOX_FILE_NAME)).unwrap();
+
+        let metadata = Salts {
+            match_id: 42,
+            cluster_id: 183,
+            metadata_salt: Some(1),
+            replay_salt: None,
+            username: Some(Identity::Pseudonym(0xab)),
+        };
+        let replay = Salts {
+            metadata_salt: None,
+            replay_salt: Some(2),
+            username: Some(Identity::Account(12345)),
+            ..metadata
+        };
+        outbox.push_salts(&[metadata, replay]).unwrap();
+        // Identities are not stored, but recomputed from the current settings
+        let username = crate::privacy::uploader_identity();
+        let pending = outbox.pending_salts().unwrap();
+        assert_eq!(
+            pending,
+            [
+                Salts {
+                    username,
+                    ..metadata
+                },
+                Salts { username, ..replay }
+            ]
+        );
+
+        outbox.remove_salts(&[metadata]).unwrap();
+        assert_eq!(
+            outbox.pending_salts().unwrap(),
+            [Salts { username, ..replay }]
+        );
+
+        outbox.push_statlocker(42).unwrap();
+        assert_eq!(outbox.pending_statlocker().unwrap(), [42]);
+        outbox.remove_statlocker(42).unwrap();
+        assert!(outbox.pending_statlocker().unwrap().is_empty());
+
+        assert_eq!(outbox.snapshot_at().unwrap(), None);
+        outbox.set_snapshot_at(1_700_000_000).unwrap();
+        assert_eq!(outbox.snapshot_at().unwrap(), Some(1_700_000_000));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
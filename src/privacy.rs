@@ -0,0 +1,186 @@
+use clap::ValueEnum;
+use core::fmt::{Display, Formatter};
+use core::str::FromStr;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use std::sync::OnceLock;
+use tracing::warn;
+
+/// Name of the file holding the per-install salt for pseudonymous ids
+const SALT_FILE_NAME: &str = "pseudonym-salt";
+
+static ANONYMITY: OnceLock<Anonymity> = OnceLock::new();
+static INSTALL_SALT: OnceLock<Option<[u8; 32]>> = OnceLock::new();
+
+/// How the uploader is identified to the Deadlock API, Statlocker and the local history.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub(crate) enum Anonymity {
+    /// Send the Steam account id
+    #[default]
+    Full,
+    /// Send a salted hash of the Steam account id that is stable for this install
+    Pseudonymous,
+    /// Send no uploader identity at all
+    None,
+}
+
+/// The identity attached to uploaded salts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum Identity {
+    Account(u32),
+    Pseudonym(u64),
+}
+
+impl Display for Identity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Identity::Account(id) => write!(f, "ingest-tool:{id}"),
+            Identity::Pseudonym(hash) => write!(f, "ingest-tool:anon-{hash:016x}"),
+        }
+    }
+}
+
+impl FromStr for Identity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let id = s
+            .strip_prefix("ingest-tool:")
+            .ok_or_else(|| format!("invalid identity: {s}"))?;
+        match id.strip_prefix("anon-") {
+            Some(hash) => u64::from_str_radix(hash, 16).map(Identity::Pseudonym),
+            None => id.parse().map(Identity::Account),
+        }
+        .map_err(|e| format!("invalid identity {s}: {e}"))
+    }
+}
+
+impl Serialize for Identity {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Identity {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+pub(crate) fn set_anonymity(anonymity: Anonymity) {
+    if ANONYMITY.set(anonymity).is_err() {
+        warn!("Anonymity setting was already configured");
+    }
+}
+
+pub(crate) fn anonymity() -> Anonymity {
+    ANONYMITY.get().copied().unwrap_or_default()
+}
+
+/// Returns the identity to attach to uploads, according to the configured anonymity setting.
+pub(crate) fn uploader_identity() -> Option<Identity> {
+    match anonymity() {
+        Anonymity::None => None,
+        Anonymity::Full => crate::steam_user::current_steam_id3().map(Identity::Account),
+        Anonymity::Pseudonymous => {
+            let account_id = crate::steam_user::current_steam_id3()?;
+            let salt = (*INSTALL_SALT.get_or_init(load_or_create_salt))?;
+            Some(Identity::Pseudonym(pseudonym(&salt, account_id)))
+        }
+    }
+}
+
+/// Human-readable description of the active setting, shown at startup.
+pub(crate) fn describe() -> String {
+    match (anonymity(), uploader_identity()) {
+        (Anonymity::None, _) => "anonymous (no uploader identity is sent)".to_string(),
+        (Anonymity::Full, Some(id)) => format!("full Steam account id ({id})"),
+        (Anonymity::Pseudonymous, Some(id)) => format!("pseudonymous id ({id})"),
+        (_, None) => "no Steam account found, uploading anonymously".to_string(),
+    }
+}
+
+fn pseudonym(salt: &[u8; 32], account_id: u32) -> u64 {
+    let mut hasher = Sha256::new();
+    hasher.update(salt);
+    hasher.update(account_id.to_le_bytes());
+    let digest = hasher.finalize();
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&digest[..8]);
+    u64::from_be_bytes(bytes)
+}
+
+fn load_or_create_salt() -> Option<[u8; 32]> {
+    let path = crate::utils::data_dir()?.join(SALT_FILE_NAME);
+    if let Some(salt) = read_salt(&path) {
+        return Some(salt);
+    }
+
+    let mut salt = [0u8; 32];
+    if let Err(e) = getrandom::fill(&mut salt) {
+        warn!("Failed to generate pseudonym salt: {e}");
+        return None;
+    }
+    match crate::utils::create_secret_file(&path, hex::encode(salt).as_bytes()) {
+        Ok(()) => Some(salt),
+        // Another process created it in the meantime
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => read_salt(&path),
+        Err(e) => {
+            warn!(
+                "Failed to persist pseudonym salt at {}: {e:?}",
+                path.display()
+            );
+            None
+        }
+    }
+}
+
+fn read_salt(path: &Path) -> Option<[u8; 32]> {
+    let content = std::fs::read_to_string(path).ok()?;
+    let mut salt = [0u8; 32];
+    hex::decode_to_slice(content.trim(), &mut salt).ok()?;
+    Some(salt)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_identity_formatting() {
+        assert_eq!(Identity::Account(12345).to_string(), "ingest-tool:12345");
+        assert_eq!(
+            Identity::Pseudonym(0xab).to_string(),
+            "ingest-tool:anon-00000000000000ab"
+        );
+        for identity in [Identity::Account(12345), Identity::Pseudonym(u64::MAX)] {
+            assert_eq!(identity.to_string().parse::<Identity>(), Ok(identity));
+        }
+        assert!("12345".parse::<Identity>().is_err());
+    }
+
+    #[test]
+    fn test_pseudonym_is_stable_and_salted() {
+        let salt_a = [1u8; 32];
+        let salt_b = [2u8; 32];
+        assert_eq!(pseudonym(&salt_a, 42), pseudonym(&salt_a, 42));
+        assert_ne!(pseudonym(&salt_a, 42), pseudonym(&salt_a, 43));
+        assert_ne!(pseudonym(&salt_a, 42), pseudonym(&salt_b, 42));
+    }
+
+    #[test]
+    fn test_salt_roundtrip() {
+        let path = std::env::temp_dir().join(format!(
+            "deadlock-pseudonym-salt-test-{}",
+            std::process::id()
+        ));
+        std::fs::write(&path, hex::encode((0u8..32).collect::<Vec<_>>())).unwrap();
+        let salt = read_salt(&path).unwrap();
+        assert_eq!(salt[0], 0);
+        assert_eq!(salt[31], 31);
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -1,5 +1,10 @@
+use crate::error::Error;
 use crate::ingestion_cache;
+use crate::match_state;
+use crate::outbox;
+use crate::shutdown;
 use crate::statlocker;
+use crate::systemd;
 use crate::utils::Salts;
 use memchr::{memchr, memmem};
 use notify::event::{CreateKind, ModifyKind};
@@ -7,21 +12,75 @@ use notify::{EventKind, RecursiveMode, Watcher};
 use std::fs;
 use std::io::Read;
 use std::path::Path;
-use tracing::{debug, info, warn};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::sync::{Mutex, OnceLock};
+use std::time::SystemTime;
+use tracing::{debug, info, info_span, warn};
 
-const DEADLOCK_APP_ID: &str = "1422450";
+pub(crate) const DEADLOCK_APP_ID: &str = "1422450";
 const MAX_BYTES_TO_READ: usize = 200;
 const SEARCH_SEQUENCE: &[u8; 10] = b".valve.net";
 const PATH_END_MARKERS: [u8; 6] = *b" '\0\n\r\"";
 
+/// How often the watcher checks for a shutdown request while no events arrive
+const WATCH_POLL_INTERVAL: core::time::Duration = core::time::Duration::from_secs(1);
+
+/// How often an idle watcher checks that the cache directory is intact and no events were missed,
+/// and retries the salts left in the outbox
+const HEALTH_CHECK_INTERVAL: core::time::Duration = core::time::Duration::from_mins(2);
+
+/// Maximum number of salts uploaded in one request, so the most urgent ones are delivered first
+const UPLOAD_CHUNK_SIZE: usize = 100;
+
+/// How long to wait before re-arming a stalled watcher
+const RESTART_DELAY: core::time::Duration = core::time::Duration::from_secs(5);
+
+/// Upper bound for the delay between attempts while the watcher cannot be armed at all
+const MAX_RESTART_DELAY: core::time::Duration = core::time::Duration::from_mins(5);
+
+/// Rescans requested by other threads, run by the watcher so that only one thread ingests.
+static RESCANS: OnceLock<(Sender<SystemTime>, Mutex<Receiver<SystemTime>>)> = OnceLock::new();
+
+fn rescans() -> &'static (Sender<SystemTime>, Mutex<Receiver<SystemTime>>) {
+    RESCANS.get_or_init(|| {
+        let (tx, rx) = std::sync::mpsc::channel();
+        (tx, Mutex::new(rx))
+    })
+}
+
+/// Asks the watcher to scan the files modified since `since` again, e.g. after the game exited.
+pub(crate) fn request_rescan(since: SystemTime) {
+    let _ = rescans().0.send(since);
+}
+
+/// Returns the earliest time a rescan was requested for since the last call.
+fn take_rescan_request() -> Option<SystemTime> {
+    let requests = rescans().1.lock().unwrap_or_else(|poisoned| {
+        warn!("Failed to lock rescan requests");
+        poisoned.into_inner()
+    });
+    requests.try_iter().min()
+}
+
 pub(super) fn scan_directory(dir: &Path, results: &mut Vec<String>) {
+    scan_modified_since(dir, SystemTime::UNIX_EPOCH, results);
+}
+
+/// Like [`scan_directory`], but only looks at files modified at or after `since`.
+fn scan_modified_since(dir: &Path, since: SystemTime, results: &mut Vec<String>) {
+    // Large caches take a while to walk, e.g. during the initial scan
+    crate::systemd::watchdog();
     if let Ok(entries) = fs::read_dir(dir) {
         for entry in entries.flatten() {
             let path = entry.path();
 
             if path.is_dir() {
-                scan_directory(&path, results);
+                scan_modified_since(&path, since, results);
             } else if path.is_file()
+                && entry
+                    .metadata()
+                    .and_then(|m| m.modified())
+                    .is_ok_and(|modified| modified >= since)
                 && let Some(url) = extract_replay_url(&path)
             {
                 let file_path = path.display().to_string();
@@ -85,71 +144,480 @@ fn extract_replay_url(path: &Path) -> Option<String> {
 }
 
 pub(super) fn initial_cache_dir_ingest(cache_dir: &Path) {
+    let _span = info_span!("initial_scan", path = %cache_dir.display()).entered();
     debug!("Scanning cache directory: {}", cache_dir.display());
     let mut results = Vec::new();
     scan_directory(cache_dir, &mut results);
-    let salts = results
+    let mut salts = results
         .into_iter()
         .filter_map(|url| Salts::from_url(&url))
         .collect::<Vec<_>>();
 
+    // Retry salts that were not delivered before the last exit
+    let pending = outbox::pending_salts();
+    if !pending.is_empty() {
+        info!(
+            "Retrying {} undelivered salts from the outbox",
+            pending.len()
+        );
+        salts.extend(pending);
+    }
+    salts.sort_unstable_by_key(|s| (s.match_id, s.metadata_salt.is_none()));
+    salts.dedup_by_key(|s| (s.match_id, s.metadata_salt.is_none()));
+
+    let new_salts = salts
+        .iter()
+        .filter(|s| has_new_salt(s))
+        .copied()
+        .collect::<Vec<_>>();
+    debug!(
+        "Skipping {} salts that were already ingested",
+        salts.len() - new_salts.len()
+    );
+
+    let planned = match_state::plan_uploads(&new_salts);
+    // Also drops outbox entries that turned out to be ingested already
+    outbox::remove_salts(&salts);
+    ingest_planned(&planned);
+}
+
+/// Whether `salts` has a metadata or replay salt that was not ingested yet.
+fn has_new_salt(salts: &Salts) -> bool {
+    (salts.metadata_salt.is_some() && !ingestion_cache::is_ingested(salts.match_id, true))
+        || (salts.replay_salt.is_some() && !ingestion_cache::is_ingested(salts.match_id, false))
+}
+
+/// Uploads the salts left in the outbox by failed uploads or a snapshot, so they are delivered
+/// once the API is reachable (or accepts this version) again rather than on the next start.
+fn drain_outbox() {
+    if crate::kill_switch::paused().is_some() {
+        return;
+    }
+    let pending = outbox::pending_salts();
+    if pending.is_empty() {
+        return;
+    }
+    info!(
+        "Retrying {} undelivered salts from the outbox",
+        pending.len()
+    );
+    let planned = match_state::plan_uploads(&pending);
+    outbox::remove_salts(&pending);
+    ingest_planned(&planned);
+}
+
+/// Scans the files modified since `since` again, to pick up salts the watcher missed
+/// (e.g. written while the game was closing) or was not running for.
+pub(super) fn recent_cache_dir_ingest(cache_dir: &Path, since: SystemTime) {
+    let _span = info_span!("recent_scan", path = %cache_dir.display()).entered();
+    debug!("Scanning recently modified cache files");
+    let mut results = Vec::new();
+    scan_modified_since(cache_dir, since, &mut results);
+    let salts = results
+        .into_iter()
+        .filter_map(|url| Salts::from_url(&url))
+        .filter(has_new_salt)
+        .collect::<Vec<_>>();
     if salts.is_empty() {
+        debug!("No new salts found in recently modified cache files");
         return;
     }
+    info!(
+        "Found {} new salts in recently modified cache files",
+        salts.len()
+    );
+    ingest_planned(&match_state::plan_uploads(&salts));
+}
 
-    match Salts::ingest_many(&salts) {
-        Ok(..) => {
-            // Mark all salts as successfully ingested in the shared cache
-            for salt in &salts {
-                ingestion_cache::mark_ingested(salt);
+/// Records the salts in the cache files modified since `since` in the outbox without uploading
+/// them, so they survive Steam evicting the cache entries until the next upload.
+/// Returns the number of salts added.
+pub(super) fn snapshot_cache_dir(cache_dir: &Path, since: SystemTime) -> usize {
+    let _span = info_span!("snapshot", path = %cache_dir.display()).entered();
+    debug!("Snapshotting recently modified cache files");
+    let mut results = Vec::new();
+    scan_modified_since(cache_dir, since, &mut results);
+    let mut salts = results
+        .into_iter()
+        .filter_map(|url| Salts::from_url(&url))
+        .filter(has_new_salt)
+        .collect::<Vec<_>>();
+    salts.sort_unstable_by_key(|s| (s.match_id, s.metadata_salt.is_none()));
+    salts.dedup_by_key(|s| (s.match_id, s.metadata_salt.is_none()));
+    outbox::push_salts(&salts);
+    salts.len()
+}
+
+/// Uploads the planned salts in chunks of [`UPLOAD_CHUNK_SIZE`] in the planned order, keeping them
+/// in the outbox until they are delivered. Stops at the first chunk that fails for a reason that
+/// may go away, leaving the rest in the outbox for a later retry.
+fn ingest_planned(planned: &[Salts]) {
+    if planned.is_empty() {
+        return;
+    }
+    outbox::push_salts(planned);
+
+    for chunk in planned.chunks(UPLOAD_CHUNK_SIZE) {
+        crate::systemd::watchdog();
+        match Salts::ingest_many(chunk) {
+            Ok(salts) => {
+                // Mark all salts as successfully ingested in the shared cache
+                for salt in &salts {
+                    ingestion_cache::mark_ingested(salt);
+                    match_state::mark_uploaded(salt);
+                }
+                outbox::remove_salts(chunk);
+                systemd::record_uploaded(salts.len());
+                let match_ids: Vec<u64> = salts.iter().map(|s| s.match_id).collect();
+                statlocker::notify_many(&match_ids);
+            }
+            Err(e) if e.is_permanent() => {
+                warn!("Failed to ingest salts: {e:?}");
+                outbox::remove_salts(chunk);
+            }
+            // Already logged once when uploads were paused, the salts wait in the outbox
+            Err(Error::Paused(_)) => return,
+            Err(e) => {
+                warn!("Failed to ingest salts, keeping them for a later retry: {e:?}");
+                return;
             }
-            let match_ids: Vec<u64> = salts.iter().map(|s| s.match_id).collect();
-            statlocker::notify_many(&match_ids);
         }
-        Err(e) => warn!("Failed to ingest salts: {e:?}"),
     }
 }
 
-pub(super) fn watch_cache_dir(cache_dir: &Path) -> notify::Result<()> {
+/// Why [`watch_cache_dir`] stopped watching.
+pub(super) enum WatchEnd {
+    /// Shutdown was requested or `keep_watching` returned false
+    Stopped,
+    /// Events may have been missed, so the watcher has to be re-armed and the cache rescanned
+    Stalled(String),
+}
+
+/// Keeps [`watch_cache_dir`] running until shutdown is requested or `keep_watching` returns false.
+/// Whenever the watcher fails or stalls it is re-armed, and the files modified since it was armed
+/// are scanned again, so salts written in the meantime are not missed.
+/// `catch_up_since` does the same for files modified before the first watcher is armed.
+pub(super) fn supervise_watcher(
+    cache_dir: &Path,
+    mut catch_up_since: Option<SystemTime>,
+    mut keep_watching: impl FnMut() -> bool,
+) {
+    let mut restarts = 0u32;
+    let mut retry_delay = RESTART_DELAY;
+    loop {
+        let armed_at = SystemTime::now();
+        let reason = match watch_cache_dir(cache_dir, catch_up_since, &mut keep_watching) {
+            Ok(WatchEnd::Stopped) => return,
+            Ok(WatchEnd::Stalled(reason)) => {
+                catch_up_since = Some(armed_at);
+                retry_delay = RESTART_DELAY;
+                reason
+            }
+            // The watcher could not be armed, so it did not catch up either
+            Err(e) => {
+                catch_up_since = Some(catch_up_since.unwrap_or(armed_at));
+                let reason = format!("failed to watch the cache directory: {e}");
+                retry_delay = (retry_delay * 2).min(MAX_RESTART_DELAY);
+                reason
+            }
+        };
+        restarts += 1;
+        warn!(
+            "Restarting cache watcher in {}s (restart {restarts}): {reason}",
+            retry_delay.as_secs()
+        );
+        systemd::watchdog();
+        if shutdown::wait_timeout(retry_delay) || !keep_watching() {
+            return;
+        }
+    }
+}
+
+/// Watches the cache directory until shutdown is requested, `keep_watching` returns false,
+/// or the watcher stops receiving events. Once the watcher is armed, the files modified since
+/// `catch_up_since` are scanned for salts that were written while nothing was watching.
+pub(super) fn watch_cache_dir(
+    cache_dir: &Path,
+    catch_up_since: Option<SystemTime>,
+    mut keep_watching: impl FnMut() -> bool,
+) -> notify::Result<WatchEnd> {
     debug!("Watching cache directory: {}", cache_dir.display());
     let (tx, rx) = std::sync::mpsc::channel();
     let mut watcher = notify::recommended_watcher(tx)?;
     watcher.watch(cache_dir, RecursiveMode::Recursive)?;
+    let created = dir_created(cache_dir);
+    if let Some(since) = catch_up_since {
+        recent_cache_dir_ingest(cache_dir, since);
+    }
 
-    while let Ok(Ok(event)) = rx.recv() {
-        let is_data_modify = matches!(event.kind, EventKind::Modify(ModifyKind::Data(_)));
-        let is_file_create = matches!(
-            event.kind,
-            EventKind::Create(CreateKind::Any | CreateKind::File)
-        );
-        if !is_data_modify && !is_file_create {
+    let mut last_event = SystemTime::now();
+    let mut last_health_check = last_event;
+    // Every event for a file written before this time has been received
+    let mut caught_up_at = last_event;
+    loop {
+        systemd::watchdog();
+        if shutdown::is_requested() || !keep_watching() {
+            return Ok(WatchEnd::Stopped);
+        }
+        if let Some(since) = take_rescan_request() {
+            recent_cache_dir_ingest(cache_dir, since);
+        }
+        let event = match rx.recv_timeout(WATCH_POLL_INTERVAL) {
+            Ok(Ok(event)) => event,
+            Ok(Err(e)) => return Ok(WatchEnd::Stalled(format!("watcher error: {e}"))),
+            Err(RecvTimeoutError::Disconnected) => {
+                return Ok(WatchEnd::Stalled(
+                    "the watcher stopped sending events".into(),
+                ));
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                let now = SystemTime::now();
+                caught_up_at = now;
+                if now
+                    .duration_since(last_health_check)
+                    .is_ok_and(|elapsed| elapsed >= HEALTH_CHECK_INTERVAL)
+                {
+                    last_health_check = now;
+                    if let Some(reason) = check_health(cache_dir, created, last_event) {
+                        return Ok(WatchEnd::Stalled(reason));
+                    }
+                    drain_outbox();
+                }
+                continue;
+            }
+        };
+        last_event = SystemTime::now();
+        if event.need_rescan() {
+            // The event queue overflowed (e.g. during a burst of cache writes), so events for
+            // anything written since the queue was last empty may be lost
+            warn!("The watcher dropped events, scanning recently modified cache files");
+            let rescan_started = SystemTime::now();
+            recent_cache_dir_ingest(cache_dir, caught_up_at - WATCH_POLL_INTERVAL);
+            caught_up_at = rescan_started;
+            continue;
+        }
+        if matches!(event.kind, EventKind::Remove(_))
+            && event.paths.iter().any(|path| path == cache_dir)
+        {
+            return Ok(WatchEnd::Stalled("the cache directory was removed".into()));
+        }
+        if !is_cache_write(event.kind) {
             continue;
         }
         for path in event.paths {
-            if path.is_file()
-                && let Some(url) = extract_replay_url(&path)
-                && let Some(salts) = Salts::from_url(&url)
+            let _span = info_span!("cache_event", path = %path.display()).entered();
+            if path.is_file() {
+                if let Some(url) = extract_replay_url(&path) {
+                    ingest_url(&url);
+                }
+            } else if path.is_dir() && matches!(event.kind, EventKind::Modify(ModifyKind::Name(_)))
             {
-                // Check if we've already ingested this salt using the shared cache
-                let is_new_metadata = salts.metadata_salt.is_some()
-                    && !ingestion_cache::is_ingested(salts.match_id, true);
-                let is_new_replay = salts.replay_salt.is_some()
-                    && !ingestion_cache::is_ingested(salts.match_id, false);
-
-                if !is_new_metadata && !is_new_replay {
-                    continue;
+                // A directory moved into the cache brings files no event was reported for
+                let mut results = Vec::new();
+                scan_directory(&path, &mut results);
+                for url in results {
+                    ingest_url(&url);
                 }
+            }
+        }
+    }
+}
 
-                match salts.ingest() {
-                    Ok(..) => {
-                        info!("Ingested salts: {salts:?}");
-                        ingestion_cache::mark_ingested(&salts);
-                        statlocker::notify(salts.match_id);
-                    }
-                    Err(e) => warn!("Failed to ingest salts: {e:?}"),
-                }
+/// Returns true for events after which a file may hold a new replay URL: data written to it,
+/// a file created, or a file (or directory) renamed, since Steam may write to a temporary file
+/// and rename it into place. For renames, the path that no longer exists is skipped later.
+fn is_cache_write(kind: EventKind) -> bool {
+    matches!(
+        kind,
+        EventKind::Modify(ModifyKind::Data(_) | ModifyKind::Name(_))
+            | EventKind::Create(CreateKind::Any | CreateKind::File)
+    )
+}
+
+/// Uploads the salts in `url` unless they were uploaded before.
+fn ingest_url(url: &str) {
+    let Some(salts) = Salts::from_url(url) else {
+        return;
+    };
+    // Check if we've already ingested this salt using the shared cache
+    let is_new_metadata =
+        salts.metadata_salt.is_some() && !ingestion_cache::is_ingested(salts.match_id, true);
+    let is_new_replay =
+        salts.replay_salt.is_some() && !ingestion_cache::is_ingested(salts.match_id, false);
+
+    if !is_new_metadata && !is_new_replay {
+        return;
+    }
+    if !match_state::needs_upload(&salts) {
+        return;
+    }
+
+    outbox::push_salts(&[salts]);
+    match salts.ingest() {
+        Ok(..) => {
+            info!("Ingested salts: {salts:?}");
+            ingestion_cache::mark_ingested(&salts);
+            match_state::mark_uploaded(&salts);
+            outbox::remove_salts(&[salts]);
+            systemd::record_uploaded(1);
+            statlocker::notify(salts.match_id);
+            debug!("Ingestion cache: {}", ingestion_cache::stats());
+        }
+        Err(e) if e.is_permanent() => {
+            warn!("Failed to ingest salts: {e:?}");
+            outbox::remove_salts(&[salts]);
+        }
+        Err(Error::Paused(_)) => {}
+        Err(e) => {
+            warn!("Failed to ingest salts, keeping them for a later retry: {e:?}");
+        }
+    }
+}
+
+/// Creation time of `dir`, to notice it being replaced. `None` if it does not exist,
+/// the Unix epoch if the file system does not record creation times.
+fn dir_created(dir: &Path) -> Option<SystemTime> {
+    fs::metadata(dir)
+        .ok()
+        .map(|metadata| metadata.created().unwrap_or(SystemTime::UNIX_EPOCH))
+}
+
+/// Returns why the watcher has to be re-armed, if the cache directory was removed or replaced
+/// (e.g. by Steam clearing its cache), or if a directory in it changed after the last event the
+/// watcher reported. Adding or removing a file changes the modification time of its directory,
+/// so only directories are looked at, not every cached file.
+fn check_health(
+    cache_dir: &Path,
+    created: Option<SystemTime>,
+    last_event: SystemTime,
+) -> Option<String> {
+    if dir_created(cache_dir) != created {
+        return Some("the cache directory was removed or replaced".into());
+    }
+    let newest = newest_dir_modification(cache_dir, SystemTime::now());
+    if newest.is_some_and(|newest| newest > last_event) {
+        return Some("files were added without the watcher reporting them".into());
+    }
+    None
+}
+
+/// Latest modification time of `dir` and the directories below it, ignoring times later than
+/// `now` (a wrong clock would otherwise look like missed events forever).
+fn newest_dir_modification(dir: &Path, now: SystemTime) -> Option<SystemTime> {
+    let modified = fs::metadata(dir)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .filter(|modified| *modified <= now);
+    let entries = fs::read_dir(dir).ok()?;
+    entries
+        .flatten()
+        .filter(|entry| entry.file_type().is_ok_and(|file_type| file_type.is_dir()))
+        .filter_map(|entry| newest_dir_modification(&entry.path(), now))
+        .chain(modified)
+        .max()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::time::Duration;
+
+    #[test]
+    fn test_scan_modified_since() {
+        let dir = std::env::temp_dir().join(format!("deadlock-scan-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("nested")).unwrap();
+        let old = dir.join("old");
+        let recent = dir.join("nested").join("recent");
+        fs::write(
+            &old,
+            "GET http://replay183.valve.net/1422450/42476710_428480166.meta.bz2 ",
+        )
+        .unwrap();
+        fs::write(
+            &recent,
+            "GET http://replay183.valve.net/1422450/42476711_428480167.dem.bz2 ",
+        )
+        .unwrap();
+        let since = SystemTime::now() - Duration::from_mins(1);
+        fs::File::options()
+            .write(true)
+            .open(&old)
+            .unwrap()
+            .set_modified(since - Duration::from_hours(1))
+            .unwrap();
+
+        let mut all = Vec::new();
+        scan_directory(&dir, &mut all);
+        assert_eq!(all.len(), 2);
+
+        let mut results = Vec::new();
+        scan_modified_since(&dir, since, &mut results);
+        assert_eq!(
+            results,
+            ["http://replay183.valve.net/1422450/42476711_428480167.dem.bz2"]
+        );
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_check_health() {
+        let dir = std::env::temp_dir().join(format!("deadlock-health-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let nested = dir.join("nested");
+        fs::create_dir_all(&nested).unwrap();
+        let created = dir_created(&dir);
+        assert!(created.is_some());
+
+        let before = SystemTime::now() - Duration::from_mins(1);
+        fs::write(nested.join("cached"), "").unwrap();
+        assert_eq!(check_health(&dir, created, SystemTime::now()), None);
+        assert!(check_health(&dir, created, before).is_some());
+
+        // Modification times in the future are not mistaken for missed events
+        #[cfg(unix)]
+        {
+            for path in [&dir, &nested] {
+                fs::File::open(path)
+                    .unwrap()
+                    .set_modified(SystemTime::now() + Duration::from_hours(1))
+                    .unwrap();
             }
+            assert_eq!(check_health(&dir, created, before), None);
         }
+
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(check_health(&dir, created, SystemTime::now()).is_some());
+    }
+
+    #[test]
+    fn test_rescan_requests() {
+        let now = SystemTime::now();
+        request_rescan(now);
+        request_rescan(now - Duration::from_mins(5));
+        assert_eq!(take_rescan_request(), Some(now - Duration::from_mins(5)));
+        assert_eq!(take_rescan_request(), None);
+    }
+
+    #[test]
+    fn test_is_cache_write() {
+        use notify::event::{DataChange, RemoveKind, RenameMode};
+
+        assert!(is_cache_write(EventKind::Modify(ModifyKind::Data(
+            DataChange::Any
+        ))));
+        assert!(is_cache_write(EventKind::Create(CreateKind::File)));
+        assert!(is_cache_write(EventKind::Modify(ModifyKind::Name(
+            RenameMode::To
+        ))));
+        assert!(is_cache_write(EventKind::Modify(ModifyKind::Name(
+            RenameMode::Both
+        ))));
+        assert!(!is_cache_write(EventKind::Create(CreateKind::Folder)));
+        assert!(!is_cache_write(EventKind::Remove(RemoveKind::File)));
+        assert!(!is_cache_write(EventKind::Access(
+            notify::event::AccessKind::Any
+        )));
     }
-    Ok(())
 }
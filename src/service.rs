@@ -0,0 +1,431 @@
+//! Running as a native Windows service.
+//!
+//! The service lifecycle (state reporting and control handling) is written against
+//! [`StatusReporter`] so it can be tested on any platform. Only talking to the service
+//! control manager is Windows-specific.
+
+use clap::Subcommand;
+
+/// Name of the service and of its Event Log source
+#[cfg(windows)]
+const SERVICE_NAME: &str = "deadlock-api-ingest";
+
+/// How long the service control manager should wait for pending uploads when stopping
+#[cfg(windows)]
+const STOP_WAIT_HINT: core::time::Duration = core::time::Duration::from_secs(15);
+
+#[derive(Subcommand, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ServiceCommand {
+    /// Register the Windows service, started at boot (requires administrator rights).
+    /// Global options given before `service` are passed on to it.
+    Install,
+    /// Stop and remove the Windows service
+    Uninstall,
+    /// Start the Windows service
+    Start,
+    /// Stop the Windows service
+    Stop,
+    /// Entry point used by the service control manager
+    #[command(hide = true)]
+    Run,
+}
+
+/// Service states reported to the service control manager.
+#[cfg(any(windows, test))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Running,
+    StopPending,
+    Stopped { exit_code: u32 },
+}
+
+/// Reports state changes to the service control manager.
+#[cfg(any(windows, test))]
+trait StatusReporter {
+    fn report(&self, state: State);
+}
+
+/// Control requests from the service control manager.
+#[cfg(any(windows, test))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Control {
+    /// Stop, or the system is shutting down
+    Stop,
+    Interrogate,
+    Other,
+}
+
+/// Handles a control request. Returns false for requests the service does not support.
+#[cfg(any(windows, test))]
+fn handle_control(control: Control, reporter: &impl StatusReporter) -> bool {
+    match control {
+        Control::Stop => {
+            tracing::info!("Stop requested by the service control manager");
+            reporter.report(State::StopPending);
+            crate::shutdown::request();
+            true
+        }
+        Control::Interrogate => true,
+        Control::Other => false,
+    }
+}
+
+/// Runs `work` as the service body: the service is reported as running until `work` returns,
+/// and then as stopped with its exit code.
+#[cfg(any(windows, test))]
+fn run_service(reporter: &impl StatusReporter, work: impl FnOnce() -> i32) {
+    reporter.report(State::Running);
+    let exit_code = work();
+    reporter.report(State::Stopped {
+        exit_code: u32::try_from(exit_code).unwrap_or(1),
+    });
+}
+
+/// Service-specific exit code to report for `exit_code`. A clean exit must be reported as
+/// `NO_ERROR`, as the service control manager treats any service-specific code as a failure
+/// and restarts the service.
+#[cfg(any(windows, test))]
+fn service_specific_code(exit_code: u32) -> Option<u32> {
+    (exit_code != 0).then_some(exit_code)
+}
+
+/// Arguments the service is started with: the global options of this invocation, then `service run`.
+#[cfg(any(windows, test))]
+fn launch_arguments(args: impl IntoIterator<Item = std::ffi::OsString>) -> Vec<std::ffi::OsString> {
+    let mut launch: Vec<_> = args
+        .into_iter()
+        .skip(1)
+        .take_while(|arg| arg != "service")
+        .collect();
+    launch.extend(["service", "run"].map(std::ffi::OsString::from));
+    launch
+}
+
+#[cfg(windows)]
+mod scm {
+    use super::{
+        Control, SERVICE_NAME, STOP_WAIT_HINT, State, StatusReporter, handle_control,
+        launch_arguments, run_service, service_specific_code,
+    };
+    use core::time::Duration;
+    use std::ffi::OsString;
+    use std::sync::OnceLock;
+    use windows_service::service::{
+        ServiceAccess, ServiceAction, ServiceActionType, ServiceControl, ServiceControlAccept,
+        ServiceErrorControl, ServiceExitCode, ServiceFailureActions, ServiceFailureResetPeriod,
+        ServiceInfo, ServiceStartType, ServiceState, ServiceStatus, ServiceType,
+    };
+    use windows_service::service_control_handler::{self, ServiceControlHandlerResult};
+    use windows_service::service_dispatcher;
+    use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+    use winreg::RegKey;
+    use winreg::enums::HKEY_LOCAL_MACHINE;
+
+    const DISPLAY_NAME: &str = "Deadlock API Ingest";
+    const DESCRIPTION: &str =
+        "Uploads Deadlock match salts found in Steam's HTTP cache to the Deadlock API.";
+    const EVENT_SOURCE_KEY: &str =
+        "SYSTEM\\CurrentControlSet\\Services\\EventLog\\Application\\deadlock-api-ingest";
+
+    static WORK: OnceLock<fn() -> i32> = OnceLock::new();
+    static STATUS_HANDLE: OnceLock<service_control_handler::ServiceStatusHandle> = OnceLock::new();
+
+    struct ScmReporter;
+
+    impl StatusReporter for ScmReporter {
+        fn report(&self, state: State) {
+            let Some(handle) = STATUS_HANDLE.get() else {
+                return;
+            };
+            let (current_state, exit_code, wait_hint) = match state {
+                State::Running => (ServiceState::Running, 0, Duration::ZERO),
+                State::StopPending => (ServiceState::StopPending, 0, STOP_WAIT_HINT),
+                State::Stopped { exit_code } => (ServiceState::Stopped, exit_code, Duration::ZERO),
+            };
+            let status = ServiceStatus {
+                service_type: ServiceType::OWN_PROCESS,
+                current_state,
+                controls_accepted: if current_state == ServiceState::Running {
+                    ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN
+                } else {
+                    ServiceControlAccept::empty()
+                },
+                exit_code: service_specific_code(exit_code)
+                    .map_or(ServiceExitCode::NO_ERROR, ServiceExitCode::ServiceSpecific),
+                checkpoint: 0,
+                wait_hint,
+                process_id: None,
+            };
+            if let Err(e) = handle.set_service_status(status) {
+                tracing::warn!("Failed to report service status: {e}");
+            }
+        }
+    }
+
+    /// Called by the service control manager on a new thread. Start arguments are not used,
+    /// the options are taken from the command line registered at install time.
+    extern "system" fn ffi_service_main(_argc: u32, _argv: *mut *mut u16) {
+        let handler = |control| {
+            let control = match control {
+                ServiceControl::Stop | ServiceControl::Shutdown => Control::Stop,
+                ServiceControl::Interrogate => Control::Interrogate,
+                _ => Control::Other,
+            };
+            if handle_control(control, &ScmReporter) {
+                ServiceControlHandlerResult::NoError
+            } else {
+                ServiceControlHandlerResult::NotImplemented
+            }
+        };
+        match service_control_handler::register(SERVICE_NAME, handler) {
+            Ok(handle) => {
+                let _ = STATUS_HANDLE.set(handle);
+            }
+            Err(e) => {
+                tracing::error!("Failed to register service control handler: {e}");
+                return;
+            }
+        }
+        if let Some(work) = WORK.get() {
+            run_service(&ScmReporter, *work);
+        }
+    }
+
+    pub(super) fn dispatch(work: fn() -> i32) -> Result<(), String> {
+        let _ = WORK.set(work);
+        service_dispatcher::start(SERVICE_NAME, ffi_service_main).map_err(|e| {
+            format!("Failed to start the service dispatcher (not started as a service?): {e}")
+        })
+    }
+
+    fn manager(access: ServiceManagerAccess) -> Result<ServiceManager, String> {
+        ServiceManager::local_computer(None::<&str>, access)
+            .map_err(|e| format!("Failed to connect to the service control manager: {e}"))
+    }
+
+    pub(super) fn install() -> Result<(), String> {
+        let executable_path = std::env::current_exe()
+            .map_err(|e| format!("Failed to determine the executable path: {e}"))?;
+        let info = ServiceInfo {
+            name: OsString::from(SERVICE_NAME),
+            display_name: OsString::from(DISPLAY_NAME),
+            service_type: ServiceType::OWN_PROCESS,
+            start_type: ServiceStartType::AutoStart,
+            error_control: ServiceErrorControl::Normal,
+            executable_path,
+            launch_arguments: launch_arguments(std::env::args_os()),
+            dependencies: vec![],
+            account_name: None,
+            account_password: None,
+        };
+        let service =
+            manager(ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE)?
+                .create_service(&info, ServiceAccess::CHANGE_CONFIG | ServiceAccess::START)
+                .map_err(|e| format!("Failed to create the service: {e}"))?;
+        service
+            .set_description(DESCRIPTION)
+            .map_err(|e| format!("Failed to set the service description: {e}"))?;
+        let restart = ServiceAction {
+            action_type: ServiceActionType::Restart,
+            delay: Duration::from_secs(10),
+        };
+        service
+            .update_failure_actions(ServiceFailureActions {
+                reset_period: ServiceFailureResetPeriod::After(Duration::from_hours(24)),
+                reboot_msg: None,
+                command: None,
+                actions: Some(vec![restart; 3]),
+            })
+            .and_then(|()| service.set_failure_actions_on_non_crash_failures(true))
+            .map_err(|e| format!("Failed to configure restarts on failure: {e}"))?;
+        register_event_source()?;
+        grant_shared_dir_access()?;
+        println!("Installed the {DISPLAY_NAME} service, start it with `service start`");
+        Ok(())
+    }
+
+    /// Registers the Event Log source, using the generic message file of `EventCreate.exe`
+    /// so messages are shown without a "description cannot be found" preamble.
+    fn register_event_source() -> Result<(), String> {
+        let system_root = std::env::var("SystemRoot").unwrap_or_else(|_| "C:\\Windows".into());
+        let (key, _) = RegKey::predef(HKEY_LOCAL_MACHINE)
+            .create_subkey(EVENT_SOURCE_KEY)
+            .map_err(|e| format!("Failed to register the Event Log source: {e}"))?;
+        key.set_value(
+            "EventMessageFile",
+            &format!("{system_root}\\System32\\EventCreate.exe"),
+        )
+        .and_then(|()| key.set_value("TypesSupported", &7u32))
+        .map_err(|e| format!("Failed to register the Event Log source: {e}"))
+    }
+
+    /// Lets every user modify the files in the shared directory. The service creates the instance
+    /// lock and IPC endpoint there as `LocalSystem`, and the launch wrapper running as the user
+    /// must be able to open them, otherwise both would watch the cache.
+    fn grant_shared_dir_access() -> Result<(), String> {
+        let shared_dir = crate::utils::shared_dir()
+            .ok_or("Failed to create the shared directory in ProgramData")?;
+        // S-1-5-32-545 is the built-in Users group, named differently in every language
+        let status = std::process::Command::new("icacls")
+            .arg(&shared_dir)
+            .args(["/grant", "*S-1-5-32-545:(OI)(CI)M", "/T", "/Q"])
+            .stdout(std::process::Stdio::null())
+            .status()
+            .map_err(|e| format!("Failed to run icacls: {e}"))?;
+        if !status.success() {
+            return Err(format!(
+                "Failed to grant users access to {} ({status})",
+                shared_dir.display()
+            ));
+        }
+        Ok(())
+    }
+
+    pub(super) fn uninstall() -> Result<(), String> {
+        let service = manager(ServiceManagerAccess::CONNECT)?
+            .open_service(
+                SERVICE_NAME,
+                ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE,
+            )
+            .map_err(|e| format!("Failed to open the service: {e}"))?;
+        if service
+            .query_status()
+            .is_ok_and(|status| status.current_state != ServiceState::Stopped)
+        {
+            let _ = service.stop();
+        }
+        service
+            .delete()
+            .map_err(|e| format!("Failed to delete the service: {e}"))?;
+        let _ = RegKey::predef(HKEY_LOCAL_MACHINE).delete_subkey_all(EVENT_SOURCE_KEY);
+        println!("Removed the {DISPLAY_NAME} service");
+        Ok(())
+    }
+
+    pub(super) fn start() -> Result<(), String> {
+        manager(ServiceManagerAccess::CONNECT)?
+            .open_service(SERVICE_NAME, ServiceAccess::START)
+            .and_then(|service| service.start::<&str>(&[]))
+            .map_err(|e| format!("Failed to start the service: {e}"))
+    }
+
+    pub(super) fn stop() -> Result<(), String> {
+        manager(ServiceManagerAccess::CONNECT)?
+            .open_service(SERVICE_NAME, ServiceAccess::STOP)
+            .and_then(|service| service.stop())
+            .map(|_| ())
+            .map_err(|e| format!("Failed to stop the service: {e}"))
+    }
+
+    pub(super) fn event_log_layer() -> Option<crate::BoxedLayer> {
+        use tracing_subscriber::Layer;
+        use tracing_subscriber::filter::LevelFilter;
+
+        match tracing_layer_win_eventlog::EventLogLayer::new_with_default_id(SERVICE_NAME, Some(1))
+        {
+            Ok(layer) => Some(layer.with_filter(LevelFilter::INFO).boxed()),
+            Err(e) => {
+                eprintln!("Failed to open the Event Log: {e}");
+                None
+            }
+        }
+    }
+}
+
+/// Installs, removes, starts or stops the Windows service.
+#[cfg(windows)]
+pub(crate) fn manage(command: ServiceCommand) -> Result<(), String> {
+    match command {
+        ServiceCommand::Install => scm::install(),
+        ServiceCommand::Uninstall => scm::uninstall(),
+        ServiceCommand::Start => scm::start(),
+        ServiceCommand::Stop => scm::stop(),
+        ServiceCommand::Run => {
+            Err("`service run` is only used by the service control manager".into())
+        }
+    }
+}
+
+#[cfg(not(windows))]
+pub(crate) fn manage(_command: ServiceCommand) -> Result<(), String> {
+    Err("The service commands are only available on Windows, use the systemd unit instead".into())
+}
+
+/// Hands control to the service control manager, which runs `work` as the service body.
+/// Blocks until the service has stopped.
+#[cfg(windows)]
+pub(crate) fn dispatch(work: fn() -> i32) -> Result<(), String> {
+    scm::dispatch(work)
+}
+
+#[cfg(not(windows))]
+pub(crate) fn dispatch(_work: fn() -> i32) -> Result<(), String> {
+    Err("The service commands are only available on Windows, use the systemd unit instead".into())
+}
+
+/// Tracing layer that writes info and above to the Windows Event Log, when running as a service.
+#[cfg(windows)]
+pub(crate) fn event_log_layer() -> Option<crate::BoxedLayer> {
+    scm::event_log_layer()
+}
+
+#[cfg(not(windows))]
+pub(crate) fn event_log_layer() -> Option<crate::BoxedLayer> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::RefCell;
+    use std::ffi::OsString;
+
+    #[derive(Default)]
+    struct RecordingReporter(RefCell<Vec<State>>);
+
+    impl StatusReporter for RecordingReporter {
+        fn report(&self, state: State) {
+            self.0.borrow_mut().push(state);
+        }
+    }
+
+    #[test]
+    fn test_service_lifecycle() {
+        // Stopping the service requests a shutdown, which is global
+        let _guard = crate::shutdown::test_guard();
+        let reporter = RecordingReporter::default();
+        run_service(&reporter, || {
+            assert!(!handle_control(Control::Other, &reporter));
+            assert!(handle_control(Control::Interrogate, &reporter));
+            assert!(handle_control(Control::Stop, &reporter));
+            assert!(crate::shutdown::is_requested());
+            0
+        });
+        assert_eq!(
+            reporter.0.into_inner(),
+            [
+                State::Running,
+                State::StopPending,
+                State::Stopped { exit_code: 0 }
+            ]
+        );
+        assert_eq!(service_specific_code(0), None);
+
+        let reporter = RecordingReporter::default();
+        run_service(&reporter, || 2);
+        assert_eq!(
+            reporter.0.into_inner(),
+            [State::Running, State::Stopped { exit_code: 2 }]
+        );
+        assert_eq!(service_specific_code(2), Some(2));
+    }
+
+    #[test]
+    fn test_launch_arguments() {
+        let args = ["exe", "--no-statlocker", "service", "install"].map(OsString::from);
+        assert_eq!(
+            launch_arguments(args),
+            ["--no-statlocker", "service", "run"].map(OsString::from)
+        );
+    }
+}
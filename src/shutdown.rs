@@ -0,0 +1,204 @@
+#[cfg(unix)]
+use core::sync::atomic::{AtomicI32, Ordering};
+use core::time::Duration;
+use std::sync::{Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::Instant;
+use tracing::{info, warn};
+
+static REQUESTED: Mutex<bool> = Mutex::new(false);
+static CONDVAR: Condvar = Condvar::new();
+
+/// Held by the tests that request a shutdown or depend on it not being requested.
+#[cfg(test)]
+static TEST_LOCK: Mutex<()> = Mutex::new(());
+
+/// Process group of the wrapped game command, 0 while there is none.
+#[cfg(unix)]
+static CHILD_GROUP: AtomicI32 = AtomicI32::new(0);
+
+/// Installs a handler for SIGINT/SIGTERM/SIGHUP (Ctrl-C and console close on Windows)
+/// that requests a graceful shutdown and forwards it to the wrapped game.
+/// A second signal kills the game and exits immediately.
+pub(crate) fn install_handler() {
+    let result = ctrlc::set_handler(|| {
+        if is_requested() {
+            warn!("Received second shutdown signal, exiting immediately");
+            signal_child(true);
+            std::process::exit(130);
+        }
+        info!("Received shutdown signal, finishing pending work");
+        signal_child(false);
+        request();
+    });
+    if let Err(e) = result {
+        warn!("Failed to install shutdown signal handler: {e}");
+    }
+}
+
+/// Registers the process group of the wrapped game command, which shutdown signals are
+/// forwarded to. The game runs in its own process group, so it does not receive them otherwise.
+/// On Windows the game shares the console and receives console events directly.
+#[cfg(unix)]
+pub(crate) fn set_child_group(pgid: Option<u32>) {
+    let pgid = pgid.and_then(|pgid| i32::try_from(pgid).ok()).unwrap_or(0);
+    CHILD_GROUP.store(pgid, Ordering::SeqCst);
+}
+
+/// Starts a guard in the wrapped game's process group that sends it SIGTERM once this process is
+/// gone. Steam stops the game by killing the wrapper with SIGKILL, which leaves nothing to forward
+/// the signal, so the guard instead waits for its stdin (held open by this process) to be closed.
+/// Kill the guard once the game has exited.
+#[cfg(unix)]
+pub(crate) fn spawn_orphan_guard(pgid: u32) -> std::io::Result<std::process::Child> {
+    use std::os::unix::process::CommandExt;
+    use std::process::{Command, Stdio};
+
+    let group = i32::try_from(pgid).map_err(std::io::Error::other)?;
+    Command::new("sh")
+        .args(["-c", r#"read -r _; kill -TERM "-$0""#])
+        .arg(pgid.to_string())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .process_group(group)
+        .spawn()
+}
+
+/// Sends SIGTERM (or SIGKILL if `force`) to the wrapped game's process group, if there is one.
+pub(crate) fn signal_child(force: bool) {
+    #[cfg(unix)]
+    {
+        use nix::sys::signal::{Signal, killpg};
+        use nix::unistd::Pid;
+
+        let pgid = CHILD_GROUP.load(Ordering::SeqCst);
+        if pgid == 0 {
+            return;
+        }
+        let signal = if force {
+            Signal::SIGKILL
+        } else {
+            Signal::SIGTERM
+        };
+        info!("Forwarding {signal} to the game (process group {pgid})");
+        if let Err(e) = killpg(Pid::from_raw(pgid), signal) {
+            warn!("Failed to forward {signal} to the game: {e}");
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = force;
+}
+
+/// Asks every long-running loop to stop and wakes up everyone waiting in [`wait_timeout`].
+pub(crate) fn request() {
+    let mut requested = REQUESTED.lock().unwrap_or_else(|poisoned| {
+        warn!("Failed to lock shutdown state");
+        poisoned.into_inner()
+    });
+    *requested = true;
+    CONDVAR.notify_all();
+}
+
+pub(crate) fn is_requested() -> bool {
+    *REQUESTED.lock().unwrap_or_else(|poisoned| {
+        warn!("Failed to lock shutdown state");
+        poisoned.into_inner()
+    })
+}
+
+/// Sleeps for `timeout` or until shutdown is requested. Returns true if shutdown was requested.
+pub(crate) fn wait_timeout(timeout: Duration) -> bool {
+    let requested = REQUESTED.lock().unwrap_or_else(|poisoned| {
+        warn!("Failed to lock shutdown state");
+        poisoned.into_inner()
+    });
+    let (requested, _) = CONDVAR
+        .wait_timeout_while(requested, timeout, |requested| !*requested)
+        .unwrap_or_else(|poisoned| {
+            warn!("Failed to lock shutdown state");
+            poisoned.into_inner()
+        });
+    *requested
+}
+
+/// Keeps the shutdown state to the calling test: other tests using it wait until this is dropped,
+/// and the state is cleared before and after.
+#[cfg(test)]
+pub(crate) struct TestGuard {
+    _lock: std::sync::MutexGuard<'static, ()>,
+}
+
+#[cfg(test)]
+pub(crate) fn test_guard() -> TestGuard {
+    let guard = TEST_LOCK
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner);
+    reset();
+    TestGuard { _lock: guard }
+}
+
+#[cfg(test)]
+impl Drop for TestGuard {
+    fn drop(&mut self) {
+        reset();
+    }
+}
+
+#[cfg(test)]
+fn reset() {
+    *REQUESTED
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner) = false;
+    #[cfg(unix)]
+    set_child_group(None);
+}
+
+/// Waits up to `timeout` for `handle` to finish. Returns false if the thread is still running.
+pub(crate) fn join_with_timeout(handle: JoinHandle<()>, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    while !handle.is_finished() {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    if handle.join().is_err() {
+        warn!("Background thread panicked");
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(unix)]
+    #[test]
+    fn test_orphan_guard_stops_game() {
+        use std::os::unix::process::{CommandExt, ExitStatusExt};
+
+        let mut game = std::process::Command::new("sleep")
+            .arg("30")
+            .process_group(0)
+            .spawn()
+            .unwrap();
+        let mut guard = super::spawn_orphan_guard(game.id()).unwrap();
+        std::thread::sleep(super::Duration::from_millis(200));
+        assert!(game.try_wait().unwrap().is_none(), "guard fired too early");
+
+        // The wrapper being killed closes the guard's stdin
+        drop(guard.stdin.take());
+        let start = super::Instant::now();
+        let status = loop {
+            if let Some(status) = game.try_wait().unwrap() {
+                break status;
+            }
+            if start.elapsed() > super::Duration::from_secs(5) {
+                let _ = game.kill();
+                panic!("game was not stopped after the wrapper was gone");
+            }
+            std::thread::sleep(super::Duration::from_millis(20));
+        };
+        assert_eq!(status.signal(), Some(15));
+        let _ = guard.wait();
+    }
+}
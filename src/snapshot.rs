@@ -0,0 +1,43 @@
+//! Offline snapshots of the Steam cache, for users who do not keep the uploader running.
+//!
+//! Steam evicts old `httpcache` entries, so the salts of matches played while nothing was watching
+//! may be gone by the time the uploader runs again. A snapshot copies the salts of the cache files
+//! modified since the previous snapshot into the outbox, without any network access, and the next
+//! regular run uploads them together with the rest of the outbox.
+
+use crate::{instance_lock, outbox, scan_cache};
+use core::time::Duration;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::info;
+
+/// Takes a snapshot of the Steam cache, unless another instance is already watching it.
+pub(crate) fn run(encrypt_history: bool) -> Result<(), String> {
+    let cache_dir = crate::locate_cache_dir()?;
+
+    let Some(_lock) = instance_lock::try_acquire(&cache_dir) else {
+        info!("Another instance is already watching the Steam cache, nothing to snapshot");
+        return Ok(());
+    };
+    crate::open_stores(encrypt_history);
+    if !outbox::is_available() {
+        return Err("The outbox is not available, cannot take a snapshot".to_string());
+    }
+
+    let started = SystemTime::now();
+    // The first snapshot covers everything still in the cache
+    let since = outbox::snapshot_at().map_or(UNIX_EPOCH, |seconds| {
+        UNIX_EPOCH + Duration::from_secs(seconds)
+    });
+    let added = scan_cache::snapshot_cache_dir(&cache_dir, since);
+    outbox::set_snapshot_at(
+        started
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs()),
+    );
+    if added == 0 {
+        info!("No new salts found in the Steam cache");
+    } else {
+        info!("Saved {added} new salts to the outbox, they are uploaded on the next run");
+    }
+    Ok(())
+}
@@ -1,50 +1,60 @@
-use core::sync::atomic::{AtomicBool, Ordering};
+use crate::outbox;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use core::time::Duration;
 use std::sync::{OnceLock, mpsc};
-use tracing::{debug, warn};
+use std::time::Instant;
+use tracing::{debug, info, info_span, warn};
+
+/// Statlocker notifications are best effort, so they give up sooner than the API uploads
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
 
 static STATLOCKER_ENABLED: AtomicBool = AtomicBool::new(true);
-static HTTP_CLIENT: OnceLock<ureq::Agent> = OnceLock::new();
+/// Number of notifications queued but not yet processed by the Statlocker thread.
+static PENDING: AtomicUsize = AtomicUsize::new(0);
 static SENDER: OnceLock<mpsc::SyncSender<u64>> = OnceLock::new();
 
-fn client() -> &'static ureq::Agent {
-    HTTP_CLIENT.get_or_init(|| {
-        ureq::Agent::config_builder()
-            .timeout_global(Some(Duration::from_secs(10)))
-            .build()
-            .new_agent()
-    })
-}
-
 fn sender() -> &'static mpsc::SyncSender<u64> {
     SENDER.get_or_init(|| {
         let (tx, rx) = mpsc::sync_channel::<u64>(1000);
         std::thread::Builder::new()
             .name("statlocker".into())
             .spawn(move || {
-                let username = crate::steam_user::current_steam_id3();
                 for match_id in rx {
-                    let url = if let Some(id) = username {
-                        format!("https://statlocker.gg/api/match/{match_id}/populate?username=ingest-tool:{id}")
+                    let _span = info_span!("notify", match_id, sink = "statlocker").entered();
+                    let url = if let Some(id) = crate::privacy::uploader_identity() {
+                        format!("https://statlocker.gg/api/match/{match_id}/populate?username={id}")
                     } else {
                         format!("https://statlocker.gg/api/match/{match_id}/populate")
                     };
                     debug!("Notifying Statlocker for match {match_id}");
 
-                    match client().get(&url).call() {
+                    let response = crate::http::client()
+                        .get(&url)
+                        .config()
+                        .timeout_global(Some(REQUEST_TIMEOUT))
+                        .build()
+                        .call();
+                    match response {
                         Ok(resp) if resp.status().is_success() => {
                             debug!("Statlocker notified successfully for match {match_id}");
+                            outbox::remove_statlocker(match_id);
                         }
                         Ok(resp) => {
                             warn!(
                                 "Statlocker returned status {} for match {match_id}",
                                 resp.status()
                             );
+                            outbox::remove_statlocker(match_id);
+                        }
+                        Err(ureq::Error::StatusCode(status)) => {
+                            warn!("Statlocker returned status {status} for match {match_id}");
+                            outbox::remove_statlocker(match_id);
                         }
                         Err(e) => {
                             warn!("Statlocker request failed for match {match_id}: {e}");
                         }
                     }
+                    PENDING.fetch_sub(1, Ordering::Relaxed);
                 }
             })
             .expect("failed to spawn statlocker thread");
@@ -61,11 +71,43 @@ pub(crate) fn notify(match_id: u64) {
         return;
     }
 
+    // Persist first, so the notification survives an exit before it was sent
+    outbox::push_statlocker(match_id);
+    PENDING.fetch_add(1, Ordering::Relaxed);
     if let Err(e) = sender().try_send(match_id) {
+        PENDING.fetch_sub(1, Ordering::Relaxed);
         warn!("Failed to enqueue Statlocker notification for match {match_id}: {e}");
     }
 }
 
+/// Re-queues notifications that were not delivered before the last exit.
+pub(crate) fn resume_pending() {
+    let pending = outbox::pending_statlocker();
+    if !pending.is_empty() && STATLOCKER_ENABLED.load(Ordering::Relaxed) {
+        info!(
+            "Retrying {} undelivered Statlocker notifications",
+            pending.len()
+        );
+        notify_many(&pending);
+    }
+}
+
+/// Waits up to `timeout` for queued notifications to be sent.
+/// Whatever is left stays in the outbox and is retried on the next start.
+pub(crate) fn flush(timeout: Duration) {
+    let deadline = Instant::now() + timeout;
+    while PENDING.load(Ordering::Relaxed) > 0 {
+        if Instant::now() >= deadline {
+            warn!(
+                "{} Statlocker notifications were not sent in time, they will be retried on the next start",
+                PENDING.load(Ordering::Relaxed)
+            );
+            return;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
 pub(crate) fn notify_many(match_ids: &[u64]) {
     let mut ids = match_ids.to_vec();
     ids.sort_unstable();
@@ -0,0 +1,56 @@
+use crate::scan_cache::DEADLOCK_APP_ID;
+use crate::vdf;
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+/// Returns the Steam library folder Deadlock is installed in, according to `libraryfolders.vdf`.
+pub(crate) fn deadlock_library(steam_path: &Path) -> Option<PathBuf> {
+    let vdf_path = steam_path.join("steamapps").join("libraryfolders.vdf");
+    let content = std::fs::read_to_string(vdf_path).ok()?;
+    find_app_library(&content, DEADLOCK_APP_ID)
+}
+
+fn find_app_library(content: &str, app_id: &str) -> Option<PathBuf> {
+    let root = match vdf::parse(content) {
+        Ok(root) => root,
+        Err(e) => {
+            warn!("Failed to parse libraryfolders.vdf: {e}");
+            return None;
+        }
+    };
+    root.get_object("libraryfolders")?
+        .objects()
+        .find(|(_, folder)| {
+            folder
+                .get_object("apps")
+                .is_some_and(|apps| apps.get(app_id).is_some())
+        })
+        .and_then(|(_, folder)| folder.get_str("path"))
+        .map(PathBuf::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_app_library() {
+        let content = r#""libraryfolders"
+{
+	"0" { "path" "/home/user/.local/share/Steam" "apps" { "228980" "1" } }
+	"1"
+	{
+		"path"		"/mnt/games/SteamLibrary"
+		"apps"
+		{
+			"1422450"		"56789"
+		}
+	}
+}"#;
+        assert_eq!(
+            find_app_library(content, DEADLOCK_APP_ID),
+            Some(PathBuf::from("/mnt/games/SteamLibrary"))
+        );
+        assert_eq!(find_app_library(content, "440"), None);
+    }
+}
@@ -1,31 +1,203 @@
-use std::collections::HashMap;
+use crate::vdf;
+use core::fmt::{Display, Formatter};
+use notify::{RecursiveMode, Watcher};
 use std::fs;
-use std::sync::OnceLock;
+use std::path::Path;
+use std::sync::RwLock;
+use tracing::{debug, info, warn};
 
 const STEAM_ID_64_IDENT: u64 = 76561197960265728;
 
-static CURRENT_STEAM_ID3: OnceLock<Option<u32>> = OnceLock::new();
+/// The resolved account, `None` until it was resolved for the first time.
+static CURRENT_USER: RwLock<Option<ActiveUser>> = RwLock::new(None);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ActiveUser {
+    id3: Option<u32>,
+    source: Option<UserSource>,
+}
+
+/// Where the active Steam account was read from, strongest signal first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum UserSource {
+    /// `ActiveProcess\ActiveUser` of the running Steam client (`registry.vdf` or the Windows registry)
+    ActiveProcess,
+    /// The `MostRecent` entry of `loginusers.vdf`
+    MostRecent,
+    /// The only account remembered in `config.vdf`
+    ConfigAccount,
+}
+
+impl Display for UserSource {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            UserSource::ActiveProcess => write!(f, "Steam's active process"),
+            UserSource::MostRecent => write!(f, "most recent login in loginusers.vdf"),
+            UserSource::ConfigAccount => write!(f, "the only account in config.vdf"),
+        }
+    }
+}
 
 /// Returns the current Steam user's account ID (`SteamID3` = ID64 - ident).
 pub(crate) fn current_steam_id3() -> Option<u32> {
-    *CURRENT_STEAM_ID3.get_or_init(|| {
-        let id64 = get_current_steam_id64()?;
-        u32::try_from(id64 - STEAM_ID_64_IDENT).ok()
-    })
+    let cached = *CURRENT_USER.read().unwrap_or_else(|poisoned| {
+        warn!("Failed to lock current Steam user for reading");
+        poisoned.into_inner()
+    });
+    cached.map_or_else(refresh_current_user, |user| user.id3)
+}
+
+/// Returns where the current Steam account was resolved from, for diagnostics.
+pub(crate) fn current_user_source() -> Option<UserSource> {
+    current_steam_id3();
+    CURRENT_USER
+        .read()
+        .unwrap_or_else(|poisoned| {
+            warn!("Failed to lock current Steam user for reading");
+            poisoned.into_inner()
+        })
+        .and_then(|user| user.source)
+}
+
+/// Re-resolves the active Steam account, e.g. after `loginusers.vdf` changed.
+pub(crate) fn refresh_current_user() -> Option<u32> {
+    set_current_user(resolve_active_user())
+}
+
+/// Replaces the cached account with `resolved`, logging if it changed.
+fn set_current_user(resolved: Option<(u32, UserSource)>) -> Option<u32> {
+    let id3 = resolved.map(|(id3, _)| id3);
+    let source = resolved.map(|(_, source)| source);
+    let previous = CURRENT_USER
+        .write()
+        .unwrap_or_else(|poisoned| {
+            warn!("Failed to lock current Steam user for writing");
+            poisoned.into_inner()
+        })
+        .replace(ActiveUser { id3, source });
+    if previous.is_some_and(|previous| previous.id3 != id3) {
+        info!(
+            "Active Steam account changed, uploader identity is now {}",
+            crate::privacy::describe()
+        );
+    }
+    id3
+}
+
+/// Watches Steam's `config` directory and re-resolves the active account whenever
+/// `loginusers.vdf` changes, so salts are attributed to the account that is logged in.
+pub(crate) fn watch_login_users(steam_path: &Path) {
+    let config_dir = steam_path.join("config");
+    let spawned = std::thread::Builder::new()
+        .name("steam-user-watcher".into())
+        .spawn(move || {
+            loop {
+                if let Err(e) = watch_config_dir(&config_dir, || {
+                    refresh_current_user();
+                }) {
+                    warn!("Error in loginusers.vdf watcher: {e:?}");
+                }
+                std::thread::sleep(core::time::Duration::from_secs(10));
+            }
+        });
+    if let Err(e) = spawned {
+        warn!("Failed to spawn loginusers.vdf watcher: {e:?}");
+    }
 }
 
-/// Get the currently logged-in Steam user's ID64 by parsing `loginusers.vdf`.
-///
-/// Steam marks the active user with `"MostRecent" "1"` in this file.
-fn get_current_steam_id64() -> Option<u64> {
+/// Calls `on_change` whenever `loginusers.vdf` in `config_dir` is written.
+fn watch_config_dir(config_dir: &Path, mut on_change: impl FnMut()) -> notify::Result<()> {
+    debug!("Watching Steam config directory: {}", config_dir.display());
+    let (tx, rx) = std::sync::mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    watcher.watch(config_dir, RecursiveMode::NonRecursive)?;
+
+    while let Ok(Ok(event)) = rx.recv() {
+        let touches_login_users = event
+            .paths
+            .iter()
+            .any(|p| p.file_name().is_some_and(|n| n == "loginusers.vdf"));
+        if touches_login_users && (event.kind.is_modify() || event.kind.is_create()) {
+            on_change();
+        }
+    }
+    Ok(())
+}
+
+fn id64_to_id3(id64: u64) -> Option<u32> {
+    u32::try_from(id64.checked_sub(STEAM_ID_64_IDENT)?).ok()
+}
+
+/// Resolves the account currently logged in to Steam, returning its `SteamID3`
+/// together with the source it was read from.
+fn resolve_active_user() -> Option<(u32, UserSource)> {
+    if let Some(id3) = active_process_user() {
+        return Some((id3, UserSource::ActiveProcess));
+    }
+
     let steam_dir = steamlocate::SteamDir::locate().ok()?;
-    let vdf_path = steam_dir.path().join("config").join("loginusers.vdf");
-    let content = fs::read_to_string(&vdf_path).ok()?;
+    resolve_from_config(&steam_dir.path().join("config"))
+}
 
-    parse_login_users(&content)
-        .into_iter()
-        .find(|u| u.most_recent)
-        .map(|u| u.steam_id)
+/// Resolves the account from the files in Steam's `config` directory.
+fn resolve_from_config(config_dir: &Path) -> Option<(u32, UserSource)> {
+    // Steam marks the last account that logged in with `"MostRecent" "1"`
+    let most_recent = fs::read_to_string(config_dir.join("loginusers.vdf"))
+        .ok()
+        .and_then(|content| {
+            parse_login_users(&content)
+                .into_iter()
+                .find(|u| u.most_recent)
+                .and_then(|u| id64_to_id3(u.steam_id))
+        });
+    if let Some(id3) = most_recent {
+        return Some((id3, UserSource::MostRecent));
+    }
+
+    let content = fs::read_to_string(config_dir.join("config.vdf")).ok()?;
+    match parse_config_accounts(&content).as_slice() {
+        [single] => Some((id64_to_id3(*single)?, UserSource::ConfigAccount)),
+        _ => None,
+    }
+}
+
+/// Reads `HKCU\Software\Valve\Steam\ActiveProcess\ActiveUser`, which is 0 while nobody is logged in.
+#[cfg(target_os = "windows")]
+fn active_process_user() -> Option<u32> {
+    use winreg::RegKey;
+    use winreg::enums::HKEY_CURRENT_USER;
+
+    let key = RegKey::predef(HKEY_CURRENT_USER)
+        .open_subkey("Software\\Valve\\Steam\\ActiveProcess")
+        .ok()?;
+    let id3: u32 = key.get_value("ActiveUser").ok()?;
+    (id3 != 0).then_some(id3)
+}
+
+/// Reads `ActiveUser` from `~/.steam/registry.vdf`, Steam's registry emulation on Linux,
+/// which is 0 while nobody is logged in.
+#[cfg(not(target_os = "windows"))]
+fn active_process_user() -> Option<u32> {
+    let content = fs::read_to_string(dirs::home_dir()?.join(".steam").join("registry.vdf")).ok()?;
+    parse_registry_active_user(&content)
+}
+
+#[cfg(not(target_os = "windows"))]
+fn parse_registry_active_user(content: &str) -> Option<u32> {
+    let root = vdf::parse(content).ok()?;
+    let id3: u32 = root
+        .get_path(&[
+            "Registry",
+            "HKCU",
+            "Software",
+            "Valve",
+            "Steam",
+            "ActiveProcess",
+        ])?
+        .get_str("ActiveUser")?
+        .parse()
+        .ok()?;
+    (id3 != 0).then_some(id3)
 }
 
 #[derive(Debug)]
@@ -35,80 +207,165 @@ struct ParsedUser {
 }
 
 fn parse_login_users(content: &str) -> Vec<ParsedUser> {
-    let mut users = Vec::new();
-    let mut lines = content.lines().peekable();
-
-    // Skip until we're inside the top-level "users" block
-    for line in lines.by_ref() {
-        if line.trim() == "{" {
-            break;
+    let root = match vdf::parse(content) {
+        Ok(root) => root,
+        Err(e) => {
+            warn!("Failed to parse loginusers.vdf: {e}");
+            return Vec::new();
         }
-    }
+    };
+    let Some(users) = root.get_object("users") else {
+        return Vec::new();
+    };
+    users
+        .objects()
+        .filter_map(|(steam_id, props)| {
+            Some(ParsedUser {
+                steam_id: steam_id.parse().ok()?,
+                most_recent: props.get_str("MostRecent") == Some("1"),
+            })
+        })
+        .collect()
+}
 
-    // Each user block: "76561198xxxxx" { ... }
-    while let Some(line) = lines.next() {
-        let trimmed = line.trim();
-        if trimmed == "}" {
-            break; // end of top-level block
+/// Returns the `SteamID` of every account remembered in `config.vdf`.
+fn parse_config_accounts(content: &str) -> Vec<u64> {
+    let root = match vdf::parse(content) {
+        Ok(root) => root,
+        Err(e) => {
+            warn!("Failed to parse config.vdf: {e}");
+            return Vec::new();
         }
+    };
+    root.get_path(&[
+        "InstallConfigStore",
+        "Software",
+        "Valve",
+        "Steam",
+        "Accounts",
+    ])
+    .map(|accounts| {
+        accounts
+            .objects()
+            .filter_map(|(_, account)| account.get_str("SteamID")?.parse().ok())
+            .collect()
+    })
+    .unwrap_or_default()
+}
 
-        // Try to read a steam ID (quoted string on its own line)
-        if let Some(steam_id) = extract_quoted(trimmed).and_then(|s| s.parse::<u64>().ok()) {
-            // Next line should be "{"
-            if lines.peek().map(|l| l.trim()) == Some("{") {
-                lines.next();
-                let props = parse_block(&mut lines);
-                users.push(ParsedUser {
-                    steam_id,
-                    most_recent: props.get("MostRecent").is_some_and(|v| v == "1"),
-                });
-            }
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_login_users() {
+        let content = r#""users"
+{
+	"76561198000000001"
+	{
+		"AccountName"		"first"
+		"MostRecent"		"0"
+	}
+	"76561198000000002" {
+		"AccountName"		"second" // comment
+		"MostRecent"		"1"
+	}
+}"#;
+        let users = parse_login_users(content);
+        assert_eq!(users.len(), 2);
+        assert!(!users[0].most_recent);
+        assert_eq!(users[1].steam_id, 76561198000000002);
+        assert!(users[1].most_recent);
     }
 
-    users
-}
+    fn login_users(most_recent: u64) -> String {
+        format!(
+            r#""users"
+{{
+	"76561198000000001" {{ "MostRecent" "{}" }}
+	"76561198000000002" {{ "MostRecent" "{}" }}
+}}"#,
+            u8::from(most_recent == 76561198000000001),
+            u8::from(most_recent == 76561198000000002),
+        )
+    }
 
-fn parse_block<'a>(lines: &mut impl Iterator<Item = &'a str>) -> HashMap<String, String> {
-    let mut map = HashMap::new();
-    for line in lines {
-        let trimmed = line.trim();
-        if trimmed == "}" {
-            break;
-        }
-        // Lines look like:  "Key"		"Value"
-        let _parts: Vec<&str> = trimmed.splitn(2, '"').collect::<Vec<_>>();
-        // Reparse: find all quoted strings
-        let quoted: Vec<String> = extract_all_quoted(trimmed);
-        if quoted.len() >= 2 {
-            map.insert(quoted[0].clone(), quoted[1].clone());
+    #[test]
+    fn test_login_users_change_updates_identity() {
+        let config_dir =
+            std::env::temp_dir().join(format!("deadlock-steam-user-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&config_dir);
+        fs::create_dir_all(&config_dir).unwrap();
+        let path = config_dir.join("loginusers.vdf");
+        fs::write(&path, login_users(76561198000000001)).unwrap();
+        set_current_user(resolve_from_config(&config_dir));
+        assert_eq!(current_steam_id3(), Some(39734273));
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let watched_dir = config_dir.clone();
+        std::thread::spawn(move || {
+            watch_config_dir(&watched_dir, || {
+                let _ = tx.send(set_current_user(resolve_from_config(&watched_dir)));
+            })
+        });
+
+        // Steam writes the file again when another account logs in
+        let timeout = core::time::Duration::from_secs(5);
+        let start = std::time::Instant::now();
+        while rx.recv_timeout(timeout / 50).ok().flatten() != Some(39734274) {
+            assert!(start.elapsed() < timeout, "login change was not noticed");
+            fs::write(&path, login_users(76561198000000002)).unwrap();
         }
+        assert_eq!(current_steam_id3(), Some(39734274));
+        let salts = crate::utils::Salts::from_url(
+            "http://replay183.valve.net/1422450/42476710_428480166.meta.bz2",
+        )
+        .unwrap();
+        // Uploads identify the account by default
+        assert_eq!(
+            salts.username,
+            Some(crate::privacy::Identity::Account(39734274))
+        );
+
+        let _ = fs::remove_dir_all(&config_dir);
     }
-    map
-}
 
-fn extract_quoted(s: &str) -> Option<String> {
-    let s = s.trim();
-    if s.starts_with('"') && s.ends_with('"') && s.len() >= 2 {
-        Some(s[1..s.len() - 1].to_string())
-    } else {
-        None
+    #[test]
+    #[cfg(not(target_os = "windows"))]
+    fn test_parse_registry_active_user() {
+        let content = r#""Registry"
+{
+	"HKCU"
+	{
+		"Software"
+		{
+			"Valve"
+			{
+				"Steam"
+				{
+					"ActiveProcess"
+					{
+						"pid"		"12345"
+						"ActiveUser"		"39734273"
+					}
+				}
+			}
+		}
+	}
+}"#;
+        assert_eq!(parse_registry_active_user(content), Some(39734273));
+        assert_eq!(
+            parse_registry_active_user(&content.replace("39734273", "0")),
+            None
+        );
     }
-}
 
-fn extract_all_quoted(s: &str) -> Vec<String> {
-    let mut results = Vec::new();
-    let mut in_quote = false;
-    let mut current = String::new();
-    for ch in s.chars() {
-        if ch == '"' {
-            if in_quote {
-                results.push(core::mem::take(&mut current));
-            }
-            in_quote = !in_quote;
-        } else if in_quote {
-            current.push(ch);
-        }
+    #[test]
+    fn test_parse_config_accounts() {
+        let content = r#""InstallConfigStore" { "Software" { "Valve" { "Steam" {
+            "Accounts" { "someone" { "SteamID" "76561198000000001" } }
+        } } } }"#;
+        assert_eq!(parse_config_accounts(content), [76561198000000001]);
+        assert!(parse_config_accounts("broken {").is_empty());
     }
-    results
 }
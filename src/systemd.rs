@@ -0,0 +1,169 @@
+//! Integration with systemd when running as a `Type=notify` service (see `module.nix`).
+//!
+//! Readiness, status and watchdog notifications are sent to `$NOTIFY_SOCKET`, so they are no-ops
+//! when the process was not started by systemd, and on other platforms.
+
+#[cfg(target_os = "linux")]
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+#[cfg(target_os = "linux")]
+use sd_notify::NotifyState;
+#[cfg(target_os = "linux")]
+use std::sync::Mutex;
+#[cfg(target_os = "linux")]
+use std::time::Instant;
+#[cfg(target_os = "linux")]
+use tracing::{debug, warn};
+
+/// What the service is currently doing, shown by `systemctl status`
+#[cfg(target_os = "linux")]
+static STATE: Mutex<&str> = Mutex::new("Starting");
+
+/// Salts uploaded since the service started, shown by `systemctl status`
+#[cfg(target_os = "linux")]
+static UPLOADED: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether READY=1 was sent. Until then, progress extends the start timeout instead.
+#[cfg(target_os = "linux")]
+static READY: AtomicBool = AtomicBool::new(false);
+
+#[cfg(target_os = "linux")]
+static LAST_WATCHDOG: Mutex<Option<Instant>> = Mutex::new(None);
+
+#[cfg(target_os = "linux")]
+fn send(state: &[NotifyState]) {
+    if let Err(e) = sd_notify::notify(state) {
+        debug!("Failed to notify systemd: {e}");
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn status_text() -> String {
+    let state = *STATE.lock().unwrap_or_else(|poisoned| {
+        warn!("Failed to lock systemd status");
+        poisoned.into_inner()
+    });
+    let uploaded = UPLOADED.load(Ordering::Relaxed);
+    match crate::kill_switch::pause_reason() {
+        Some(reason) => format!("{state}, {uploaded} salts uploaded, uploads paused: {reason}"),
+        None => format!("{state}, {uploaded} salts uploaded"),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn store_state(state: &'static str) {
+    *STATE.lock().unwrap_or_else(|poisoned| {
+        warn!("Failed to lock systemd status");
+        poisoned.into_inner()
+    }) = state;
+}
+
+/// Tells systemd that startup is complete, which also arms the watchdog.
+pub(crate) fn ready(state: &'static str) {
+    #[cfg(target_os = "linux")]
+    {
+        store_state(state);
+        READY.store(true, Ordering::Relaxed);
+        send(&[NotifyState::Ready, NotifyState::Status(&status_text())]);
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = state;
+}
+
+/// Updates what the service is doing, e.g. "Watching the Steam cache".
+pub(crate) fn set_state(state: &'static str) {
+    #[cfg(target_os = "linux")]
+    {
+        store_state(state);
+        send(&[NotifyState::Status(&status_text())]);
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = state;
+}
+
+/// Adds `count` to the number of uploaded salts shown in the status.
+pub(crate) fn record_uploaded(count: usize) {
+    #[cfg(target_os = "linux")]
+    {
+        UPLOADED.fetch_add(count, Ordering::Relaxed);
+        send(&[NotifyState::Status(&status_text())]);
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = count;
+}
+
+/// Sends the status again, e.g. after uploads were paused.
+pub(crate) fn refresh_status() {
+    #[cfg(target_os = "linux")]
+    send(&[NotifyState::Status(&status_text())]);
+}
+
+/// Tells systemd the service is still alive. Must be called regularly from every long-running
+/// loop, so that a loop that stops making progress gets the service restarted.
+/// Pings are rate-limited to twice per watchdog interval, so this is cheap to call often.
+///
+/// Before [`ready`], e.g. while the initial scan uploads a large cache, each ping extends the
+/// start timeout by one watchdog interval, so `TimeoutStartSec` only limits a startup that stops
+/// making progress.
+pub(crate) fn watchdog() {
+    #[cfg(target_os = "linux")]
+    {
+        let Some(interval) = sd_notify::watchdog_enabled() else {
+            return;
+        };
+        let mut last = LAST_WATCHDOG.lock().unwrap_or_else(|poisoned| {
+            warn!("Failed to lock systemd watchdog state");
+            poisoned.into_inner()
+        });
+        let now = Instant::now();
+        if last.is_some_and(|last| now.duration_since(last) < interval / 2) {
+            return;
+        }
+        *last = Some(now);
+        if READY.load(Ordering::Relaxed) {
+            send(&[NotifyState::Watchdog]);
+        } else {
+            let usec = u32::try_from(interval.as_micros()).unwrap_or(u32::MAX);
+            send(&[NotifyState::ExtendTimeoutUsec(usec)]);
+        }
+    }
+}
+
+/// Tells systemd the service is shutting down.
+pub(crate) fn stopping() {
+    #[cfg(target_os = "linux")]
+    send(&[NotifyState::Stopping, NotifyState::Status("Shutting down")]);
+}
+
+/// Returns a layer logging to journald with structured fields (e.g. `MATCH_ID`, `PATH`),
+/// if stdout is connected to the journal, in which case it replaces plain stdout logging.
+pub(crate) fn journald_layer() -> Option<crate::BoxedLayer> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::fs::MetadataExt;
+        use tracing_subscriber::Layer;
+
+        // systemd sets `JOURNAL_STREAM` to `<device>:<inode>` of the stream it connected
+        let journal_stream = std::env::var("JOURNAL_STREAM").ok()?;
+        let stdout = std::fs::metadata("/proc/self/fd/1").ok()?;
+        if journal_stream != format!("{}:{}", stdout.dev(), stdout.ino()) {
+            return None;
+        }
+        let layer = tracing_journald::layer().ok()?.with_field_prefix(None);
+        Some(layer.boxed())
+    }
+    #[cfg(not(target_os = "linux"))]
+    None
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_text() {
+        set_state("Watching the Steam cache");
+        record_uploaded(2);
+        record_uploaded(1);
+        assert_eq!(status_text(), "Watching the Steam cache, 3 salts uploaded");
+    }
+}
@@ -0,0 +1,333 @@
+//! Optional check for new releases on GitHub (`--update`).
+//!
+//! With `--update install`, a newer release is downloaded, its SHA-256 checksum and Ed25519
+//! signature are verified, and it atomically replaces this binary, taking effect on the next start.
+//! The release workflow signs the binaries with the key matching [`PUBLIC_KEY`]. Builds without an
+//! embedded public key only notify, as they cannot tell a genuine release from a tampered one.
+
+use clap::ValueEnum;
+use core::time::Duration;
+use ring::signature::{ED25519, UnparsedPublicKey};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io;
+use std::path::Path;
+use tracing::{debug, info, warn};
+
+pub(crate) const DEFAULT_UPDATE_URL: &str =
+    "https://api.github.com/repos/deadlock-api/deadlock-api-ingest/releases/latest";
+
+/// How often a long-running instance checks for a new release
+const CHECK_INTERVAL: Duration = Duration::from_hours(24);
+
+/// Upper bound for downloaded release binaries
+const MAX_BINARY_SIZE: u64 = 100 * 1024 * 1024;
+
+/// Upper bound for reading a downloaded file, longer than for API responses
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_mins(5);
+
+/// Hex-encoded Ed25519 public key that release binaries are signed with, set at build time.
+const PUBLIC_KEY: Option<&str> = option_env!("DEADLOCK_UPDATE_PUBLIC_KEY");
+
+/// Name of the release asset built for this platform, see `.github/workflows/main.yml`
+#[cfg(target_os = "windows")]
+const ASSET_NAME: &str = "deadlock-api-ingest-windows-latest.exe";
+#[cfg(not(target_os = "windows"))]
+const ASSET_NAME: &str = "deadlock-api-ingest-ubuntu-latest";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub(crate) enum UpdateMode {
+    /// Never check for new releases
+    #[default]
+    Off,
+    /// Log when a new release is available
+    Notify,
+    /// Download and verify new releases and replace this binary, used from the next start
+    Install,
+}
+
+#[derive(Debug, Deserialize)]
+struct Release {
+    tag_name: String,
+    html_url: String,
+    assets: Vec<Asset>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Asset {
+    name: String,
+    browser_download_url: String,
+}
+
+impl Release {
+    fn asset_url(&self, name: &str) -> Result<&str, String> {
+        self.assets
+            .iter()
+            .find(|asset| asset.name == name)
+            .map(|asset| asset.browser_download_url.as_str())
+            .ok_or_else(|| format!("Release {} has no asset {name}", self.tag_name))
+    }
+}
+
+/// Parses `v1.2.3` (or `v1.2.3-<sha>` for pre-releases) into its numeric parts.
+fn parse_version(version: &str) -> Option<(u64, u64, u64)> {
+    let version = version.trim_start_matches('v');
+    let version = version.split_once('-').map_or(version, |(base, _)| base);
+    let mut parts = version.split('.').map(|part| part.parse().ok());
+    let parsed = (parts.next()??, parts.next()??, parts.next()??);
+    parts.next().is_none().then_some(parsed)
+}
+
+/// Returns true if the version `tag` is newer than `current`.
+pub(crate) fn is_newer(tag: &str, current: &str) -> bool {
+    parse_version(tag)
+        .zip(parse_version(current))
+        .is_some_and(|(tag, current)| tag > current)
+}
+
+fn public_key() -> Option<Vec<u8>> {
+    let key = PUBLIC_KEY.filter(|key| !key.is_empty())?;
+    hex::decode(key)
+        .inspect_err(|e| warn!("Invalid update public key: {e}"))
+        .ok()
+}
+
+/// Starts checking for new releases in the background, unless `mode` is [`UpdateMode::Off`].
+pub(crate) fn start(mode: UpdateMode, url: &str) {
+    remove_replaced_binary();
+    if mode == UpdateMode::Off {
+        return;
+    }
+    let url = url.to_string();
+    let spawned = std::thread::Builder::new()
+        .name("update-check".into())
+        .spawn(move || {
+            let mut installed = None;
+            loop {
+                if let Err(e) = check(mode, &url, &mut installed) {
+                    warn!("Failed to check for updates: {e}");
+                }
+                if crate::shutdown::wait_timeout(CHECK_INTERVAL) {
+                    return;
+                }
+            }
+        });
+    if let Err(e) = spawned {
+        warn!("Failed to spawn update check thread: {e}");
+    }
+}
+
+/// Checks for a newer release and installs it if `mode` says so.
+/// `installed` remembers the release already installed by this process.
+fn check(mode: UpdateMode, url: &str, installed: &mut Option<String>) -> Result<(), String> {
+    let release: Release = crate::http::client()
+        .get(url)
+        .header("Accept", "application/vnd.github+json")
+        .call()
+        .and_then(|mut response| response.body_mut().read_json())
+        .map_err(|e| e.to_string())?;
+    let current = env!("CARGO_PKG_VERSION");
+    if !is_newer(&release.tag_name, current) {
+        debug!("Running the latest release ({current})");
+        return Ok(());
+    }
+    if installed.as_ref() == Some(&release.tag_name) {
+        return Ok(());
+    }
+    info!(
+        "Version {} is available (running {current}): {}",
+        release.tag_name, release.html_url
+    );
+    if mode != UpdateMode::Install {
+        return Ok(());
+    }
+    let Some(public_key) = public_key() else {
+        warn!("This build cannot verify release signatures, please update manually");
+        return Ok(());
+    };
+
+    let binary = download_verified(&release, &public_key)?;
+    install(&binary).map_err(|e| format!("Failed to replace the binary: {e}"))?;
+    info!(
+        "Installed version {}, it is used from the next start",
+        release.tag_name
+    );
+    *installed = Some(release.tag_name);
+    Ok(())
+}
+
+fn download(url: &str) -> Result<Vec<u8>, String> {
+    crate::http::client()
+        .get(url)
+        .config()
+        .timeout_recv_body(Some(DOWNLOAD_TIMEOUT))
+        .build()
+        .call()
+        .and_then(|mut response| {
+            response
+                .body_mut()
+                .with_config()
+                .limit(MAX_BINARY_SIZE)
+                .read_to_vec()
+        })
+        .map_err(|e| format!("Failed to download {url}: {e}"))
+}
+
+/// Downloads this platform's binary from `release` with its checksum and signature,
+/// and returns it if both match.
+fn download_verified(release: &Release, public_key: &[u8]) -> Result<Vec<u8>, String> {
+    let binary = download(release.asset_url(ASSET_NAME)?)?;
+    let checksum = download(release.asset_url(&format!("{ASSET_NAME}.sha256"))?)?;
+    let signature = download(release.asset_url(&format!("{ASSET_NAME}.sig"))?)?;
+    verify(
+        &binary,
+        &String::from_utf8_lossy(&checksum),
+        &signature,
+        public_key,
+    )?;
+    Ok(binary)
+}
+
+/// Checks `binary` against a `sha256sum` style checksum and a detached Ed25519 signature.
+fn verify(
+    binary: &[u8],
+    checksum: &str,
+    signature: &[u8],
+    public_key: &[u8],
+) -> Result<(), String> {
+    let expected = checksum.split_whitespace().next().unwrap_or_default();
+    if !hex::encode(Sha256::digest(binary)).eq_ignore_ascii_case(expected) {
+        return Err("Checksum of the downloaded binary does not match".to_string());
+    }
+    UnparsedPublicKey::new(&ED25519, public_key)
+        .verify(binary, signature)
+        .map_err(|_| "Signature of the downloaded binary is invalid".to_string())
+}
+
+/// Replaces the running binary with `binary`. The new file is written next to it first,
+/// so the binary is never left half written.
+fn install(binary: &[u8]) -> io::Result<()> {
+    let exe = std::env::current_exe()?;
+    let staged = exe.with_extension("new");
+    fs::write(&staged, binary)?;
+    fs::set_permissions(&staged, fs::metadata(&exe)?.permissions())?;
+    replace(&staged, &exe).inspect_err(|_| {
+        let _ = fs::remove_file(&staged);
+    })
+}
+
+#[cfg(not(target_os = "windows"))]
+fn replace(staged: &Path, exe: &Path) -> io::Result<()> {
+    fs::rename(staged, exe)
+}
+
+/// A running executable cannot be overwritten on Windows, but it can be renamed,
+/// so it is moved aside and removed on the next start.
+#[cfg(target_os = "windows")]
+fn replace(staged: &Path, exe: &Path) -> io::Result<()> {
+    let old = exe.with_extension("old");
+    let _ = fs::remove_file(&old);
+    fs::rename(exe, &old)?;
+    fs::rename(staged, exe).inspect_err(|_| {
+        let _ = fs::rename(&old, exe);
+    })
+}
+
+/// Removes the binary moved aside by the last update on Windows.
+fn remove_replaced_binary() {
+    #[cfg(target_os = "windows")]
+    if let Ok(exe) = std::env::current_exe() {
+        let _ = fs::remove_file(exe.with_extension("old"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{MockResponse, MockServer};
+    use ring::rand::SystemRandom;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    fn key_pair() -> Ed25519KeyPair {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap()
+    }
+
+    #[test]
+    fn test_is_newer() {
+        assert_eq!(parse_version("v0.2.10"), Some((0, 2, 10)));
+        assert_eq!(parse_version("v0.2.9-abc1234"), Some((0, 2, 9)));
+        assert_eq!(parse_version("nightly"), None);
+        assert!(is_newer("v0.2.10", "0.2.9"));
+        assert!(is_newer("v1.0.0", "0.9.9"));
+        assert!(!is_newer("v0.2.9", "0.2.9"));
+        assert!(!is_newer("v0.2.9-abc1234", "0.2.9"));
+        assert!(!is_newer("v0.2.8", "0.2.9"));
+        assert!(!is_newer("latest", "0.2.9"));
+    }
+
+    #[test]
+    fn test_verify() {
+        let key = key_pair();
+        let binary = b"new binary";
+        let checksum = format!("{}  {ASSET_NAME}\n", hex::encode(Sha256::digest(binary)));
+        let signature = key.sign(binary);
+        let public_key = key.public_key().as_ref();
+
+        assert!(verify(binary, &checksum, signature.as_ref(), public_key).is_ok());
+        assert!(verify(b"tampered", &checksum, signature.as_ref(), public_key).is_err());
+        let other_key = key_pair();
+        assert!(
+            verify(
+                binary,
+                &checksum,
+                other_key.sign(binary).as_ref(),
+                public_key
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_download_verified() {
+        let key = key_pair();
+        let binary = b"new binary".to_vec();
+        let server = MockServer::start(vec![
+            MockResponse::bytes(200, binary.clone()),
+            MockResponse::bytes(200, hex::encode(Sha256::digest(&binary)).into_bytes()),
+            MockResponse::bytes(200, key.sign(&binary).as_ref().to_vec()),
+        ]);
+        let asset = |name: &str| Asset {
+            name: name.to_string(),
+            browser_download_url: format!("{}/{name}", server.url),
+        };
+        let release = Release {
+            tag_name: "v999.0.0".to_string(),
+            html_url: String::new(),
+            assets: vec![
+                asset(ASSET_NAME),
+                asset(&format!("{ASSET_NAME}.sha256")),
+                asset(&format!("{ASSET_NAME}.sig")),
+            ],
+        };
+
+        let downloaded = download_verified(&release, key.public_key().as_ref()).unwrap();
+        assert_eq!(downloaded, binary);
+        let requests = server.requests();
+        assert_eq!(requests.len(), 3);
+        assert!(requests[2].request_line.contains(".sig"));
+    }
+
+    #[test]
+    fn test_check_notify() {
+        let server = MockServer::start(vec![MockResponse::json(
+            200,
+            r#"{"tag_name": "v999.0.0", "html_url": "https://example.com", "assets": []}"#,
+        )]);
+        let mut installed = None;
+        check(UpdateMode::Notify, &server.url, &mut installed).unwrap();
+        assert_eq!(installed, None);
+        assert_eq!(server.requests().len(), 1);
+    }
+}
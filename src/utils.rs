@@ -1,35 +1,104 @@
+use crate::api_key::{self, ApiKey};
 use crate::error::Error;
+use crate::kill_switch;
+use crate::privacy::Identity;
+use crate::validation::{self, ValidationRules};
 use core::time::Duration;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::sync::OnceLock;
-use std::thread::sleep;
-use tracing::debug;
+use tracing::{debug, info_span, warn};
 use ureq::Error::StatusCode;
 
-static HTTP_CLIENT: OnceLock<ureq::Agent> = OnceLock::new();
+const DEFAULT_API_URL: &str = "https://api.deadlock-api.com";
 
-#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+static API_URL: OnceLock<String> = OnceLock::new();
+
+/// Overrides the base URL of the Deadlock API, e.g. to point at a local mock.
+pub(crate) fn set_api_url(url: &str) {
+    if API_URL.set(url.trim_end_matches('/').to_string()).is_err() {
+        warn!("API URL was already configured");
+    }
+}
+
+pub(crate) fn api_url() -> &'static str {
+    API_URL.get().map_or(DEFAULT_API_URL, String::as_str)
+}
+
+/// Builds an upload to `/v1/matches/salts`, authenticated with `api_key` if there is one.
+fn salts_request(
+    api_url: &str,
+    api_key: Option<&ApiKey>,
+) -> ureq::RequestBuilder<ureq::typestate::WithBody> {
+    let request = crate::http::client().post(format!("{api_url}/v1/matches/salts"));
+    match api_key {
+        Some(api_key) => request.header("Authorization", api_key.bearer()),
+        None => request,
+    }
+}
+
+/// Returns the application data directory, creating it if needed.
+/// - Linux: `~/.local/share/deadlock-api-ingest/`
+/// - macOS: `~/Library/Application Support/deadlock-api-ingest/`
+/// - Windows: `C:\Users\<User>\AppData\Roaming\deadlock-api-ingest\`
+pub(crate) fn data_dir() -> Option<PathBuf> {
+    let data_dir = dirs::data_dir()?.join("deadlock-api-ingest");
+    if let Err(e) = std::fs::create_dir_all(&data_dir) {
+        warn!(
+            "Failed to create data directory at {}: {e:?}",
+            data_dir.display()
+        );
+        return None;
+    }
+    Some(data_dir)
+}
+
+/// Creates `path` with `contents`, readable only by the current user on Unix. Fails if the file
+/// already exists, so a secret is never replaced by another process or written through a link.
+pub(crate) fn create_secret_file(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(contents)
+}
+
+/// Returns the directory for files shared by every instance on this machine, creating it if
+/// needed, so instances running as different accounts find each other.
+/// - Windows: `C:\ProgramData\deadlock-api-ingest\`, reachable by the service (running as
+///   `LocalSystem`) and by the launch wrapper (running as the user)
+/// - Elsewhere: the data directory, as the service runs as the Steam user
+pub(crate) fn shared_dir() -> Option<PathBuf> {
+    #[cfg(target_os = "windows")]
+    {
+        let program_data = std::env::var_os("ProgramData")
+            .map_or_else(|| PathBuf::from("C:\\ProgramData"), PathBuf::from);
+        let shared_dir = program_data.join("deadlock-api-ingest");
+        if let Err(e) = std::fs::create_dir_all(&shared_dir) {
+            warn!(
+                "Failed to create shared directory at {}: {e:?}",
+                shared_dir.display()
+            );
+            return None;
+        }
+        Some(shared_dir)
+    }
+    #[cfg(not(target_os = "windows"))]
+    data_dir()
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub(super) struct Salts {
     pub(super) match_id: u64,
     pub(super) cluster_id: u32,
     pub(super) metadata_salt: Option<u32>,
     pub(super) replay_salt: Option<u32>,
-    #[serde(
-        skip_serializing_if = "Option::is_none",
-        serialize_with = "serialize_username"
-    )]
-    pub(super) username: Option<u32>,
-}
-
-#[allow(clippy::trivially_copy_pass_by_ref, clippy::ref_option)]
-fn serialize_username<S: serde::Serializer>(
-    value: &Option<u32>,
-    serializer: S,
-) -> Result<S::Ok, S::Error> {
-    match value {
-        Some(id) => serializer.serialize_str(&format!("ingest-tool:{id}")),
-        None => serializer.serialize_none(),
-    }
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(super) username: Option<Identity>,
 }
 
 impl Salts {
@@ -52,7 +121,7 @@ impl Salts {
                 match_id: match_str.parse().ok()?,
                 metadata_salt: salt_str.parse().ok(),
                 replay_salt: None,
-                username: crate::steam_user::current_steam_id3(),
+                username: crate::privacy::uploader_identity(),
             })
         } else if name.ends_with(".dem.bz2") {
             let name = name.strip_suffix(".dem.bz2")?;
@@ -63,28 +132,56 @@ impl Salts {
                 match_id: match_str.parse().ok()?,
                 replay_salt: salt_str.parse().ok(),
                 metadata_salt: None,
-                username: crate::steam_user::current_steam_id3(),
+                username: crate::privacy::uploader_identity(),
             })
         } else {
             None
         }
     }
 
-    pub(crate) fn ingest(&self) -> Result<(), Error> {
-        if self.match_id > 100000000 {
-            return Err(Error::MatchIdTooLarge);
+    /// Checks the salts against the given limits before they are uploaded.
+    pub(crate) fn validate(&self, rules: &ValidationRules) -> Result<(), Error> {
+        if !(rules.min_match_id..=rules.max_match_id).contains(&self.match_id) {
+            return Err(Error::InvalidSalts(format!(
+                "match id {} is outside {}..={}",
+                self.match_id, rules.min_match_id, rules.max_match_id
+            )));
         }
+        if !(rules.min_cluster_id..=rules.max_cluster_id).contains(&self.cluster_id) {
+            return Err(Error::InvalidSalts(format!(
+                "cluster id {} is outside {}..={}",
+                self.cluster_id, rules.min_cluster_id, rules.max_cluster_id
+            )));
+        }
+        match (self.metadata_salt, self.replay_salt) {
+            (None, None) => Err(Error::InvalidSalts("no salt present".to_string())),
+            (Some(0), _) | (_, Some(0)) => Err(Error::InvalidSalts("salt is zero".to_string())),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn ingest(&self) -> Result<(), Error> {
+        let _span = info_span!(
+            "ingest",
+            match_id = self.match_id,
+            cluster_id = self.cluster_id,
+            sink = "deadlock-api"
+        )
+        .entered();
+        self.validate(&validation::rules())?;
 
         let max_retries = 10;
         let mut attempt = 0;
 
         loop {
             attempt += 1;
+            // Retries can take minutes, which must not trip the watchdog of the calling loop
+            crate::systemd::watchdog();
+            if let Some(reason) = kill_switch::paused() {
+                return Err(Error::Paused(reason));
+            }
             debug!("Ingesting salts: {self:?} (retry {attempt}/{max_retries})");
-            let response = HTTP_CLIENT
-                .get_or_init(ureq::Agent::new_with_defaults)
-                .post("https://api.deadlock-api.com/v1/matches/salts")
-                .send_json([self]);
+            let response = salts_request(api_url(), api_key::get()).send_json([self]);
             match response {
                 Ok(r) if r.status().is_success() => return Ok(()),
                 Ok(mut resp) if attempt == max_retries => {
@@ -94,29 +191,54 @@ impl Salts {
                 Err(e) if attempt == max_retries || matches!(e, StatusCode(s) if s == 400) => {
                     return Err(Error::Ureq(e));
                 }
-                _ => sleep(Duration::from_secs(3)), // Retry on error
+                // Retry on error, unless we are shutting down
+                _ => {
+                    if crate::shutdown::wait_timeout(Duration::from_secs(3)) {
+                        return Err(Error::Interrupted);
+                    }
+                }
             }
         }
     }
 
-    pub(crate) fn ingest_many(salts: &[Salts]) -> Result<(), Error> {
+    /// Validates and uploads the given salts, returning the ones that were accepted.
+    pub(crate) fn ingest_many(salts: &[Salts]) -> Result<Vec<Salts>, Error> {
+        let rules = validation::rules();
+        let salts: Vec<Salts> = salts
+            .iter()
+            .filter(|salt| match salt.validate(&rules) {
+                Ok(()) => true,
+                Err(e) => {
+                    warn!("Skipping invalid salts {salt:?}: {e:?}");
+                    false
+                }
+            })
+            .copied()
+            .collect();
+        if salts.is_empty() {
+            return Ok(salts);
+        }
+
         let max_retries = 10;
         let num_salts = salts.len();
+        let _span = info_span!("ingest_many", count = num_salts, sink = "deadlock-api").entered();
         let mut attempt = 0;
         loop {
             attempt += 1;
+            // Retries can take minutes, which must not trip the watchdog of the calling loop
+            crate::systemd::watchdog();
+            if let Some(reason) = kill_switch::paused() {
+                return Err(Error::Paused(reason));
+            }
             if attempt > 1 {
                 debug!("Ingesting {num_salts} salts (retry {attempt}/{max_retries})");
             } else {
                 debug!("Ingesting {num_salts} salts");
             }
 
-            let response = HTTP_CLIENT
-                .get_or_init(ureq::Agent::new_with_defaults)
-                .post("https://api.deadlock-api.com/v1/matches/salts")
-                .send_json(salts);
+            let response = salts_request(api_url(), api_key::get()).send_json(&salts);
             match response {
-                Ok(r) if r.status().is_success() => return Ok(()),
+                Ok(r) if r.status().is_success() => return Ok(salts),
                 Ok(mut resp) if attempt == max_retries => {
                     let text = resp.body_mut().read_to_string().unwrap_or_default();
                     return Err(Error::FailedToIngest(text));
@@ -124,7 +246,12 @@ impl Salts {
                 Err(e) if attempt == max_retries || matches!(e, StatusCode(s) if s == 400) => {
                     return Err(Error::Ureq(e));
                 }
-                _ => sleep(Duration::from_secs(3)), // Retry on error
+                // Retry on error, unless we are shutting down
+                _ => {
+                    if crate::shutdown::wait_timeout(Duration::from_secs(3)) {
+                        return Err(Error::Interrupted);
+                    }
+                }
             }
         }
     }
@@ -133,6 +260,29 @@ impl Salts {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_utils::{MockResponse, MockServer};
+
+    #[test]
+    fn test_create_secret_file() {
+        let path =
+            std::env::temp_dir().join(format!("deadlock-secret-test-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        create_secret_file(&path, b"secret").unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"secret");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        // An existing secret is never replaced
+        let error = create_secret_file(&path, b"other").unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::AlreadyExists);
+        assert_eq!(std::fs::read(&path).unwrap(), b"secret");
+
+        let _ = std::fs::remove_file(&path);
+    }
 
     #[test]
     fn test_extract_salts() {
@@ -190,4 +340,90 @@ mod tests {
             assert_eq!(salts.replay_salt, replay_salt);
         }
     }
+
+    #[test]
+    fn test_extracted_salts_are_valid() {
+        for url in [
+            "http://replay404.valve.net/1422450/37959196_937530290.meta.bz2",
+            "http://replay183.valve.net/1422450/42476710_428480166.dem.bz2?v=2",
+        ] {
+            let salts = Salts::from_url(url).unwrap();
+            assert!(salts.validate(&ValidationRules::default()).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_validate_salts() {
+        let rules = ValidationRules {
+            min_match_id: 1,
+            max_match_id: 100,
+            min_cluster_id: 1,
+            max_cluster_id: 10,
+        };
+        let valid = Salts {
+            match_id: 50,
+            cluster_id: 5,
+            metadata_salt: Some(1),
+            replay_salt: None,
+            username: None,
+        };
+        assert!(valid.validate(&rules).is_ok());
+
+        let cases = [
+            Salts {
+                match_id: 101,
+                ..valid
+            },
+            Salts {
+                match_id: 0,
+                ..valid
+            },
+            Salts {
+                cluster_id: 11,
+                ..valid
+            },
+            Salts {
+                metadata_salt: Some(0),
+                ..valid
+            },
+            Salts {
+                metadata_salt: None,
+                ..valid
+            },
+        ];
+        for salts in cases {
+            assert!(
+                matches!(salts.validate(&rules), Err(Error::InvalidSalts(_))),
+                "{salts:?} should be invalid"
+            );
+        }
+    }
+
+    #[test]
+    fn test_salts_request_authorization() {
+        let server = MockServer::start(vec![
+            MockResponse::json(200, "{}"),
+            MockResponse::json(200, "{}"),
+        ]);
+        let api_key = ApiKey::parse("secret-key");
+        salts_request(&server.url, api_key.as_ref())
+            .send_json([0])
+            .unwrap();
+        salts_request(&server.url, None).send_json([0]).unwrap();
+
+        let authorization = |request: &crate::test_utils::MockRequest| {
+            request
+                .headers
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case("authorization"))
+                .map(|(_, value)| value.clone())
+        };
+        let requests = server.requests();
+        assert_eq!(requests[0].request_line, "POST /v1/matches/salts HTTP/1.1");
+        assert_eq!(
+            authorization(&requests[0]).as_deref(),
+            Some("Bearer secret-key")
+        );
+        assert_eq!(authorization(&requests[1]), None);
+    }
 }
@@ -0,0 +1,267 @@
+use crate::http;
+use crate::utils::api_url;
+use core::time::Duration;
+use serde::Deserialize;
+use std::sync::Mutex;
+use std::time::Instant;
+use tracing::{debug, warn};
+
+/// How long a fetched config is used before it is fetched again
+const REFRESH_INTERVAL: Duration = Duration::from_hours(6);
+/// How soon to try again after the config could not be fetched
+const RETRY_INTERVAL: Duration = Duration::from_mins(5);
+
+static CONFIG: Mutex<Option<CachedConfig>> = Mutex::new(None);
+
+/// Config as last fetched, or the local defaults if that failed, and when to fetch it again.
+#[derive(Debug, Clone, Copy)]
+struct CachedConfig {
+    config: IngestConfig,
+    expires_at: Instant,
+}
+
+/// What the API tells clients about ingestion at `/v1/ingest/config`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub(crate) struct IngestConfig {
+    #[serde(flatten)]
+    pub(crate) rules: ValidationRules,
+    /// Whether the API answers which salts it already has at `/v1/matches/salts/status`
+    pub(crate) supports_salt_status: bool,
+}
+
+/// Limits a salt must satisfy before it is uploaded.
+/// Fetched from the API so they can change without a client release, with a safe local default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+#[allow(clippy::struct_field_names)]
+pub(crate) struct ValidationRules {
+    pub(crate) min_match_id: u64,
+    pub(crate) max_match_id: u64,
+    pub(crate) min_cluster_id: u32,
+    pub(crate) max_cluster_id: u32,
+}
+
+impl Default for ValidationRules {
+    fn default() -> Self {
+        Self {
+            min_match_id: 1,
+            max_match_id: 10_000_000_000,
+            min_cluster_id: 1,
+            max_cluster_id: 65_535,
+        }
+    }
+}
+
+impl ValidationRules {
+    /// Whether every range is non-empty, as an empty one would refuse every salt.
+    fn is_valid(&self) -> bool {
+        self.min_match_id <= self.max_match_id && self.min_cluster_id <= self.max_cluster_id
+    }
+}
+
+impl IngestConfig {
+    fn fetch(base_url: &str) -> Option<Self> {
+        let response = http::client()
+            .get(format!("{base_url}/v1/ingest/config"))
+            .call();
+        match response {
+            Ok(mut resp) => match resp.body_mut().read_json::<Self>() {
+                Ok(mut config) => {
+                    if !config.rules.is_valid() {
+                        warn!(
+                            "Ignoring invalid validation rules from the ingest config, using local defaults: {:?}",
+                            config.rules
+                        );
+                        config.rules = ValidationRules::default();
+                    }
+                    Some(config)
+                }
+                Err(e) => {
+                    warn!("Failed to parse ingest config: {e}");
+                    None
+                }
+            },
+            Err(e) => {
+                debug!("Failed to fetch ingest config, using local defaults: {e}");
+                None
+            }
+        }
+    }
+}
+
+/// Returns the ingest config, fetching it from the API on first use and again every
+/// [`REFRESH_INTERVAL`], so a running instance picks up changes.
+fn config() -> IngestConfig {
+    cached_config(&CONFIG, Instant::now(), || IngestConfig::fetch(api_url()))
+}
+
+/// Returns the validation rules from the ingest config.
+pub(crate) fn rules() -> ValidationRules {
+    config().rules
+}
+
+/// Returns true if the API advertises the salt status endpoint.
+pub(crate) fn supports_salt_status() -> bool {
+    config().supports_salt_status
+}
+
+/// Returns the config in `cache`, calling `fetch` first if it expired by `now`. When fetching
+/// fails, the previous config (or the defaults) is kept and fetching is retried sooner.
+///
+/// The lock is not held while fetching, so other callers are not blocked by a slow API: they keep
+/// using the previous config until the fetch is done.
+fn cached_config(
+    cache: &Mutex<Option<CachedConfig>>,
+    now: Instant,
+    fetch: impl FnOnce() -> Option<IngestConfig>,
+) -> IngestConfig {
+    let lock = || {
+        cache.lock().unwrap_or_else(|poisoned| {
+            warn!("Failed to lock ingest config");
+            poisoned.into_inner()
+        })
+    };
+    let previous = {
+        let mut cached = lock();
+        if let Some(cached) = *cached
+            && now < cached.expires_at
+        {
+            return cached.config;
+        }
+        let previous = cached.map(|cached| cached.config).unwrap_or_default();
+        // Claims the fetch, so other callers do not fetch at the same time
+        *cached = Some(CachedConfig {
+            config: previous,
+            expires_at: now + RETRY_INTERVAL,
+        });
+        previous
+    };
+
+    let (config, expires_at) = match fetch() {
+        Some(config) => (config, now + REFRESH_INTERVAL),
+        None => (previous, now + RETRY_INTERVAL),
+    };
+    debug!("Using ingest config: {config:?}");
+    *lock() = Some(CachedConfig { config, expires_at });
+    config
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{MockResponse, MockServer};
+
+    #[test]
+    fn test_fetch_partial_config() {
+        let server = MockServer::start(vec![MockResponse::json(
+            200,
+            r#"{"max_match_id":20000000000}"#,
+        )]);
+        let config = IngestConfig::fetch(&server.url).unwrap();
+        assert_eq!(config.rules.max_match_id, 20_000_000_000);
+        assert_eq!(
+            config.rules.min_match_id,
+            ValidationRules::default().min_match_id
+        );
+        assert!(!config.supports_salt_status);
+        assert_eq!(
+            server.requests()[0].request_line,
+            "GET /v1/ingest/config HTTP/1.1"
+        );
+    }
+
+    #[test]
+    fn test_fetch_salt_status_support() {
+        let server = MockServer::start(vec![MockResponse::json(
+            200,
+            r#"{"supports_salt_status":true}"#,
+        )]);
+        let config = IngestConfig::fetch(&server.url).unwrap();
+        assert!(config.supports_salt_status);
+        assert_eq!(config.rules, ValidationRules::default());
+    }
+
+    #[test]
+    fn test_config_is_refreshed() {
+        let cache = Mutex::new(None);
+        let start = Instant::now();
+        let fetched = IngestConfig {
+            rules: ValidationRules {
+                max_match_id: 20_000_000_000,
+                ..ValidationRules::default()
+            },
+            supports_salt_status: true,
+        };
+
+        // A failed fetch falls back to the defaults and is retried sooner
+        assert_eq!(
+            cached_config(&cache, start, || None),
+            IngestConfig::default()
+        );
+        let now = start + RETRY_INTERVAL / 2;
+        assert_eq!(
+            cached_config(&cache, now, || panic!("retried too soon")),
+            IngestConfig::default()
+        );
+        let now = start + RETRY_INTERVAL;
+        assert_eq!(cached_config(&cache, now, || Some(fetched)), fetched);
+
+        // A fetched config is kept until it expires, and also when refreshing it fails
+        let later = now + REFRESH_INTERVAL / 2;
+        assert_eq!(
+            cached_config(&cache, later, || panic!("refreshed too soon")),
+            fetched
+        );
+        let later = now + REFRESH_INTERVAL;
+        assert_eq!(cached_config(&cache, later, || None), fetched);
+        let changed = IngestConfig {
+            supports_salt_status: false,
+            ..fetched
+        };
+        assert_eq!(
+            cached_config(&cache, later + RETRY_INTERVAL, || Some(changed)),
+            changed
+        );
+    }
+
+    #[test]
+    fn test_fetch_invalid_rules() {
+        let server = MockServer::start(vec![MockResponse::json(
+            200,
+            r#"{"min_match_id":20,"max_match_id":10,"supports_salt_status":true}"#,
+        )]);
+        let config = IngestConfig::fetch(&server.url).unwrap();
+        assert_eq!(config.rules, ValidationRules::default());
+        assert!(config.supports_salt_status);
+    }
+
+    #[test]
+    fn test_config_is_not_locked_while_fetching() {
+        let cache = Mutex::new(None);
+        let now = Instant::now();
+        let fetched = IngestConfig {
+            supports_salt_status: true,
+            ..IngestConfig::default()
+        };
+        let config = cached_config(&cache, now, || {
+            // Another caller meanwhile gets the previous config without fetching again
+            assert_eq!(
+                cached_config(&cache, now, || panic!("fetched twice")),
+                IngestConfig::default()
+            );
+            Some(fetched)
+        });
+        assert_eq!(config, fetched);
+        assert_eq!(
+            cached_config(&cache, now, || panic!("fetched again")),
+            fetched
+        );
+    }
+
+    #[test]
+    fn test_fetch_failure() {
+        let server = MockServer::start(vec![MockResponse::json(500, "")]);
+        assert!(IngestConfig::fetch(&server.url).is_none());
+    }
+}
@@ -0,0 +1,371 @@
+//! Parser for Valve's text `KeyValues` format (`.vdf` files such as `loginusers.vdf`).
+
+use core::fmt::{Display, Formatter};
+
+/// Maximum nesting depth accepted, to keep malformed input from overflowing the stack.
+const MAX_DEPTH: usize = 64;
+
+/// A value in a `KeyValues` tree: either a string or a nested block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Value {
+    String(String),
+    Object(Object),
+}
+
+/// A block of key-value pairs. Keys keep their file order and may repeat.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Object(Vec<(String, Value)>);
+
+impl Object {
+    /// Returns the first value for `key`. Keys are matched case-insensitively, like Steam does.
+    pub(crate) fn get(&self, key: &str) -> Option<&Value> {
+        self.0
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v)
+    }
+
+    pub(crate) fn get_str(&self, key: &str) -> Option<&str> {
+        match self.get(key)? {
+            Value::String(s) => Some(s),
+            Value::Object(_) => None,
+        }
+    }
+
+    pub(crate) fn get_object(&self, key: &str) -> Option<&Object> {
+        match self.get(key)? {
+            Value::Object(o) => Some(o),
+            Value::String(_) => None,
+        }
+    }
+
+    /// Follows a path of nested blocks, e.g. `["Software", "Valve", "Steam"]`.
+    pub(crate) fn get_path(&self, path: &[&str]) -> Option<&Object> {
+        path.iter()
+            .try_fold(self, |object, key| object.get_object(key))
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&str, &Value)> {
+        self.0.iter().map(|(k, v)| (k.as_str(), v))
+    }
+
+    /// Iterates over the nested blocks, skipping plain string values.
+    pub(crate) fn objects(&self) -> impl Iterator<Item = (&str, &Object)> {
+        self.iter().filter_map(|(k, v)| match v {
+            Value::Object(o) => Some((k, o)),
+            Value::String(_) => None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ParseError {
+    pub(crate) line: usize,
+    pub(crate) message: &'static str,
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Token {
+    String(String),
+    Open,
+    Close,
+}
+
+struct Parser<'a> {
+    chars: core::iter::Peekable<core::str::Chars<'a>>,
+    line: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, message: &'static str) -> ParseError {
+        ParseError {
+            line: self.line,
+            message,
+        }
+    }
+
+    /// Skips whitespace, `//` comments and `[$PLATFORM]` conditionals.
+    fn skip_trivia(&mut self) {
+        while let Some(&c) = self.chars.peek() {
+            match c {
+                '\n' => {
+                    self.line += 1;
+                    self.chars.next();
+                }
+                c if c.is_whitespace() => {
+                    self.chars.next();
+                }
+                '/' => {
+                    let mut lookahead = self.chars.clone();
+                    lookahead.next();
+                    if lookahead.peek() != Some(&'/') {
+                        return;
+                    }
+                    while self.chars.next_if(|&c| c != '\n').is_some() {}
+                }
+                '[' => while self.chars.next().is_some_and(|c| c != ']') {},
+                _ => return,
+            }
+        }
+    }
+
+    fn next_token(&mut self) -> Result<Option<Token>, ParseError> {
+        self.skip_trivia();
+        let Some(c) = self.chars.next() else {
+            return Ok(None);
+        };
+        match c {
+            '{' => Ok(Some(Token::Open)),
+            '}' => Ok(Some(Token::Close)),
+            '"' => {
+                let mut s = String::new();
+                loop {
+                    match self.chars.next() {
+                        None => return Err(self.error("unterminated string")),
+                        Some('"') => return Ok(Some(Token::String(s))),
+                        Some('\\') => match self.chars.next() {
+                            Some('n') => s.push('\n'),
+                            Some('t') => s.push('\t'),
+                            Some(escaped @ ('\\' | '"')) => s.push(escaped),
+                            Some(other) => {
+                                s.push('\\');
+                                s.push(other);
+                            }
+                            None => return Err(self.error("unterminated string")),
+                        },
+                        Some(c) => {
+                            if c == '\n' {
+                                self.line += 1;
+                            }
+                            s.push(c);
+                        }
+                    }
+                }
+            }
+            c => {
+                let mut s = String::from(c);
+                while let Some(c) = self
+                    .chars
+                    .next_if(|&c| !c.is_whitespace() && !matches!(c, '{' | '}' | '"'))
+                {
+                    s.push(c);
+                }
+                Ok(Some(Token::String(s)))
+            }
+        }
+    }
+
+    /// Parses key-value pairs until the closing brace (or end of input at the top level).
+    fn parse_object(&mut self, depth: usize) -> Result<Object, ParseError> {
+        if depth > MAX_DEPTH {
+            return Err(self.error("nesting too deep"));
+        }
+        let mut object = Object::default();
+        loop {
+            let key = match self.next_token()? {
+                Some(Token::String(key)) => key,
+                Some(Token::Close) if depth > 0 => return Ok(object),
+                None if depth == 0 => return Ok(object),
+                Some(Token::Close) => return Err(self.error("unexpected '}'")),
+                Some(Token::Open) => return Err(self.error("expected key, found '{'")),
+                None => return Err(self.error("unexpected end of input")),
+            };
+            let value = match self.next_token()? {
+                Some(Token::String(value)) => Value::String(value),
+                Some(Token::Open) => Value::Object(self.parse_object(depth + 1)?),
+                Some(Token::Close) => return Err(self.error("expected value, found '}'")),
+                None => return Err(self.error("missing value")),
+            };
+            object.0.push((key, value));
+        }
+    }
+}
+
+/// Parses a text `KeyValues` document into its root block.
+pub(crate) fn parse(input: &str) -> Result<Object, ParseError> {
+    let input = input.strip_prefix('\u{feff}').unwrap_or(input);
+    Parser {
+        chars: input.chars().peekable(),
+        line: 1,
+    }
+    .parse_object(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOGIN_USERS: &str = r#""users"
+{
+	"76561198000000001"
+	{
+		"AccountName"		"first"
+		"PersonaName"		"Some \"quoted\" name"
+		"RememberPassword"		"1"
+		"MostRecent"		"0"
+		"Timestamp"		"1700000000"
+	}
+	"76561198000000002"
+	{
+		"AccountName"		"second"
+		"PersonaName"		"Back\\slash"
+		"mostrecent"		"1"
+	}
+}
+"#;
+
+    const LIBRARY_FOLDERS: &str = r#""libraryfolders"
+{
+	"0"
+	{
+		"path"		"/home/user/.local/share/Steam"
+		"label"		""
+		"contentid"		"1234567890"
+		"apps"
+		{
+			"228980"		"1234"
+			"1422450"		"56789"
+		}
+	}
+	"1"
+	{
+		"path"		"D:\\SteamLibrary"
+		"apps"
+		{
+		}
+	}
+}
+"#;
+
+    const CONFIG: &str = r#""InstallConfigStore"
+{
+	"Software"
+	{
+		"Valve"
+		{
+			"Steam"
+			{
+				"Accounts"
+				{
+					"first"
+					{
+						"SteamID"		"76561198000000001"
+					}
+				}
+				"CompatToolMapping" { "0" { "name" "proton" } }
+			}
+		}
+	}
+}
+"#;
+
+    #[test]
+    fn test_parse_login_users() {
+        let root = parse(LOGIN_USERS).unwrap();
+        let users = root.get_object("users").unwrap();
+        let (id, user) = users.objects().nth(1).unwrap();
+        assert_eq!(id, "76561198000000002");
+        assert_eq!(user.get_str("MostRecent"), Some("1"));
+        assert_eq!(user.get_str("PersonaName"), Some("Back\\slash"));
+        let (_, first) = users.objects().next().unwrap();
+        assert_eq!(first.get_str("PersonaName"), Some("Some \"quoted\" name"));
+    }
+
+    #[test]
+    fn test_parse_library_folders() {
+        let root = parse(LIBRARY_FOLDERS).unwrap();
+        let folders = root.get_object("libraryfolders").unwrap();
+        let (_, first) = folders.objects().next().unwrap();
+        assert!(first.get_object("apps").unwrap().get("1422450").is_some());
+        let (_, second) = folders.objects().nth(1).unwrap();
+        assert_eq!(second.get_str("path"), Some("D:\\SteamLibrary"));
+        assert_eq!(second.get_object("apps").unwrap().iter().count(), 0);
+    }
+
+    #[test]
+    fn test_parse_config_same_line_braces() {
+        let root = parse(CONFIG).unwrap();
+        let steam = root
+            .get_path(&["InstallConfigStore", "Software", "Valve", "Steam"])
+            .unwrap();
+        let accounts = steam.get_object("Accounts").unwrap();
+        assert_eq!(
+            accounts.get_object("first").unwrap().get_str("SteamID"),
+            Some("76561198000000001")
+        );
+        let compat = steam.get_path(&["CompatToolMapping", "0"]).unwrap();
+        assert_eq!(compat.get_str("name"), Some("proton"));
+    }
+
+    #[test]
+    fn test_comments_conditionals_and_unquoted() {
+        let input = "// header comment\nroot {\n  key value // trailing\n  \"win\" \"1\" [$WIN32]\n  url \"http://a/b\"\n}\n";
+        let root = parse(input).unwrap();
+        let block = root.get_object("root").unwrap();
+        assert_eq!(block.get_str("key"), Some("value"));
+        assert_eq!(block.get_str("win"), Some("1"));
+        assert_eq!(block.get_str("url"), Some("http://a/b"));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            parse("\"a\" {\n\"b\" \"c\"\n").unwrap_err().message,
+            "unexpected end of input"
+        );
+        assert_eq!(
+            parse("\"a\" \"b").unwrap_err().message,
+            "unterminated string"
+        );
+        assert_eq!(parse("}").unwrap_err().message, "unexpected '}'");
+        assert_eq!(parse("\"a\"").unwrap_err().message, "missing value");
+        assert_eq!(
+            parse(&"a {".repeat(MAX_DEPTH + 2)).unwrap_err().message,
+            "nesting too deep"
+        );
+    }
+
+    /// Feeds truncated and randomly mutated real-world samples to the parser, which must never panic.
+    #[test]
+    fn test_fuzz_samples() {
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        let alphabet = [
+            '"', '{', '}', '\\', '/', '\n', '[', ']', ' ', 'x', '\u{feff}',
+        ];
+
+        for sample in [LOGIN_USERS, LIBRARY_FOLDERS, CONFIG] {
+            for end in 0..=sample.len() {
+                if sample.is_char_boundary(end) {
+                    let _ = parse(&sample[..end]);
+                }
+            }
+            for _ in 0..2_000 {
+                let mut chars: Vec<char> = sample.chars().collect();
+                for _ in 0..=next() % 4 {
+                    let pos = usize::try_from(next() % chars.len() as u64).unwrap();
+                    let c = alphabet[usize::try_from(next() % alphabet.len() as u64).unwrap()];
+                    match next() % 3 {
+                        0 => chars[pos] = c,
+                        1 => chars.insert(pos, c),
+                        _ => {
+                            chars.remove(pos);
+                        }
+                    }
+                }
+                let _ = parse(&chars.into_iter().collect::<String>());
+            }
+        }
+    }
+}
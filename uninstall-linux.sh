@@ -53,6 +53,8 @@ if systemctl --user is-active "$SERVICE_NAME" &>/dev/null; then
 fi
 systemctl --user stop "$SERVICE_NAME" 2>/dev/null || true
 systemctl --user disable "$SERVICE_NAME" 2>/dev/null || true
+systemctl --user stop "$SERVICE_NAME"-snapshot.timer 2>/dev/null || true
+systemctl --user disable "$SERVICE_NAME"-snapshot.timer 2>/dev/null || true
 
 # Remove systemd user unit files
 echo -e "${CYAN}Removing service files...${NC}"
@@ -60,6 +62,11 @@ if [[ -f "$HOME/.config/systemd/user/$SERVICE_NAME.service" ]]; then
     echo -e "  ${GRAY}- Removing user service file${NC}"
     rm -f "$HOME/.config/systemd/user/$SERVICE_NAME.service"
 fi
+if [[ -f "$HOME/.config/systemd/user/$SERVICE_NAME-snapshot.timer" ]]; then
+    echo -e "  ${GRAY}- Removing snapshot timer${NC}"
+    rm -f "$HOME/.config/systemd/user/$SERVICE_NAME-snapshot.timer" \
+        "$HOME/.config/systemd/user/$SERVICE_NAME-snapshot.service"
+fi
 
 # Reload systemd user state
 systemctl --user daemon-reload 2>/dev/null || true
@@ -37,7 +37,8 @@ Write-Host "Removing scheduled tasks..." -ForegroundColor Cyan
 $tasksToRemove = @(
     "$AppName",
     "$AppName-Watchdog",
-    "$AppName-updater"
+    "$AppName-updater",
+    "$AppName-snapshot"
 )
 
 foreach ($taskName in $tasksToRemove) {
@@ -49,6 +50,21 @@ foreach ($taskName in $tasksToRemove) {
     }
 }
 
+# Stop and remove the Windows service, if it was installed
+$service = Get-Service -Name $AppName -ErrorAction SilentlyContinue
+if ($service) {
+    Write-Host "Removing Windows service..." -ForegroundColor Cyan
+    $exePath = Join-Path -Path $InstallDir -ChildPath "$AppName.exe"
+    if (Test-Path $exePath) {
+        # Also removes the Event Log source (requires administrator rights)
+        & $exePath service uninstall
+    }
+    else {
+        Stop-Service -Name $AppName -Force -ErrorAction SilentlyContinue
+        & sc.exe delete $AppName | Out-Null
+    }
+}
+
 # Stop any running process
 Write-Host "Stopping running processes..." -ForegroundColor Cyan
 $processes = Get-Process -Name $AppName -ErrorAction SilentlyContinue
@@ -79,6 +95,13 @@ if (Test-Path $InstallDir) {
     Remove-Item $InstallDir -Recurse -Force -ErrorAction SilentlyContinue
 }
 
+# Instance lock and IPC files shared by the service and the launch wrapper
+$SharedDir = Join-Path -Path $env:ProgramData -ChildPath $AppName
+if (Test-Path $SharedDir) {
+    Write-Host "  - Removing: $SharedDir" -ForegroundColor Gray
+    Remove-Item $SharedDir -Recurse -Force -ErrorAction SilentlyContinue
+}
+
 Write-Host ""
 Write-Host "========================================" -ForegroundColor Green
 Write-Host "  Uninstallation Complete!" -ForegroundColor Green